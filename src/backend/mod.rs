@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use std::time::Duration;
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+
+mod openai;
+mod retry;
+
+pub use self::openai::OpenAiBackend;
+pub use self::retry::{RetryingBackend, RetryPolicy};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Role {
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompletionMessage {
+    pub role: Role,
+    pub name: Option<String>,
+    pub content: String,
+}
+
+/// Sampling parameters, `None` or empty values are left to the provider defaults.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CompletionParams {
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u64>,
+    pub presence_penalty: Option<f32>,
+    pub frequency_penalty: Option<f32>,
+    pub stop: Vec<String>,
+}
+
+#[derive(Clone, Copy)]
+pub struct CompletionRequest<'a> {
+    pub model: &'a str,
+    pub prompt: &'a str,
+    /// summary of the earlier conversation, sent right after the system prompt
+    pub summary: Option<&'a str>,
+    pub messages: &'a [CompletionMessage],
+    pub params: &'a CompletionParams,
+}
+
+/// A chat completion provider: takes a system prompt and the chat history, returns the reply text.
+#[async_trait]
+pub trait CompletionBackend: Sync {
+    async fn complete(&self, request: CompletionRequest<'_>) -> Result<String, BackendError>;
+
+    /// Same as `complete`, but every piece of the reply is also sent to `deltas` as soon as it is generated.
+    /// Backends without streaming support send the whole reply at once.
+    async fn complete_stream(&self, request: CompletionRequest<'_>, deltas: UnboundedSender<String>) -> Result<String, BackendError> {
+        let text = self.complete(request).await?;
+        let _ = deltas.send(text.clone());
+        Ok(text)
+    }
+}
+
+/// Lets the chats share one backend.
+#[async_trait]
+impl<B: CompletionBackend + Send> CompletionBackend for Arc<B> {
+    async fn complete(&self, request: CompletionRequest<'_>) -> Result<String, BackendError> {
+        self.as_ref().complete(request).await
+    }
+
+    async fn complete_stream(&self, request: CompletionRequest<'_>, deltas: UnboundedSender<String>) -> Result<String, BackendError> {
+        self.as_ref().complete_stream(request, deltas).await
+    }
+}
+
+/// Backend failures classified by whether the request is worth repeating.
+#[derive(Debug, Error)]
+pub enum BackendError {
+    /// too many requests, `retry_after` is the delay asked by the provider
+    #[error("rate limited: {message}")]
+    RateLimited { message: String, retry_after: Option<Duration> },
+    /// server or network failure
+    #[error("transient error: {0}")]
+    Transient(String),
+    /// invalid key, exceeded context length or any other bad request, repeating it won't help
+    #[error("{0}")]
+    Permanent(String),
+}
@@ -0,0 +1,260 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use log::debug;
+use openai::{ApiResponse, OpenAiError};
+use openai::chat::{ChatCompletion, ChatCompletionDelta, ChatCompletionMessage, ChatCompletionMessageRole, ChatCompletionRequest};
+use reqwest::{Client, Response, StatusCode};
+use reqwest::header::RETRY_AFTER;
+use serde::Deserialize;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::backend::{BackendError, CompletionBackend, CompletionMessage, CompletionRequest, Role};
+
+pub const DEFAULT_BASE_URL: &str = "https://api.openai.com/v1/";
+
+/// OpenAI chat completions API client. Works with any server exposing the same API.
+pub struct OpenAiBackend {
+    client: Client,
+    base_url: String,
+    api_key: String,
+}
+
+impl OpenAiBackend {
+    pub fn new(api_key: String, base_url: Option<String>) -> Self {
+        let mut base_url = base_url.unwrap_or(DEFAULT_BASE_URL.to_string());
+        if !base_url.ends_with('/') {
+            base_url.push('/');
+        }
+
+        Self {
+            client: Client::new(),
+            base_url,
+            api_key,
+        }
+    }
+}
+
+#[async_trait]
+impl CompletionBackend for OpenAiBackend {
+    async fn complete(&self, request: CompletionRequest<'_>) -> Result<String, BackendError> {
+        let body = build_request(&request, false)?;
+        let response = self.post(&body)
+            .await?
+            .json::<ApiResponse<ChatCompletion>>()
+            .await
+            .map_err(BackendError::from)?;
+
+        let chat = match response {
+            ApiResponse::Ok(chat) => chat,
+            ApiResponse::Err { error } => return Err(BackendError::Permanent(error.message)),
+        };
+
+        let first = chat.choices.into_iter().next().ok_or(BackendError::Permanent("No choices".to_string()))?;
+        Ok(first.message.content)
+    }
+
+    async fn complete_stream(&self, request: CompletionRequest<'_>, deltas: UnboundedSender<String>) -> Result<String, BackendError> {
+        let body = build_request(&request, true)?;
+        let mut response = self.post(&body).await?;
+
+        // server-sent events, a chunk may end in the middle of a line or of a character
+        let mut text = String::new();
+        let mut buffer = Vec::new();
+        while let Some(chunk) = response.chunk().await.map_err(BackendError::from)? {
+            buffer.extend_from_slice(&chunk);
+            while let Some(line) = take_line(&mut buffer) {
+                if let Some(delta) = parse_event(line.trim_end()).map_err(|e| BackendError::Permanent(e.to_string()))? {
+                    text.push_str(&delta);
+                    // the receiver may have given up on rendering, the full text is still returned
+                    let _ = deltas.send(delta);
+                }
+            }
+        }
+
+        Ok(text)
+    }
+}
+
+impl OpenAiBackend {
+    /// Sends the request, an unsuccessful response is turned into a classified error.
+    async fn post(&self, body: &ChatCompletionRequest) -> Result<Response, BackendError> {
+        let url = format!("{}chat/completions", self.base_url);
+        debug!("requesting completion from {}", url);
+
+        let response = self.client.post(url)
+            .bearer_auth(&self.api_key)
+            .json(body)
+            .send()
+            .await?;
+
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+
+        let retry_after = response.headers().get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let error = response.json::<ErrorResponse>().await.ok().map(|response| response.error);
+
+        Err(classify(status, error, retry_after))
+    }
+}
+
+#[derive(Deserialize)]
+struct ErrorResponse {
+    error: OpenAiError,
+}
+
+fn classify(status: StatusCode, error: Option<OpenAiError>, retry_after: Option<Duration>) -> BackendError {
+    let code = error.as_ref().and_then(|error| error.code.clone());
+    let message = match error {
+        Some(error) => format!("{}: {}", status, error.message),
+        None => status.to_string(),
+    };
+
+    match status {
+        // an exhausted quota is reported as 429 too, but doesn't recover by waiting
+        StatusCode::TOO_MANY_REQUESTS if code.as_deref() != Some("insufficient_quota") => BackendError::RateLimited { message, retry_after },
+        StatusCode::REQUEST_TIMEOUT => BackendError::Transient(message),
+        status if status.is_server_error() => BackendError::Transient(message),
+        _ => BackendError::Permanent(message),
+    }
+}
+
+impl From<reqwest::Error> for BackendError {
+    fn from(e: reqwest::Error) -> Self {
+        if e.is_decode() {
+            BackendError::Permanent(e.to_string())
+        } else {
+            BackendError::Transient(e.to_string())
+        }
+    }
+}
+
+fn build_request(request: &CompletionRequest<'_>, stream: bool) -> Result<ChatCompletionRequest, BackendError> {
+    let mut messages = Vec::with_capacity(request.messages.len() + 2);
+    messages.push(ChatCompletionMessage {
+        role: ChatCompletionMessageRole::System,
+        name: None,
+        content: request.prompt.to_string(),
+    });
+    if let Some(summary) = request.summary {
+        messages.push(ChatCompletionMessage {
+            role: ChatCompletionMessageRole::System,
+            name: None,
+            content: format!("Summary of the earlier conversation:\n{}", summary),
+        });
+    }
+    messages.extend(request.messages.iter().map(to_openai_message));
+
+    let params = request.params;
+    let mut builder = ChatCompletion::builder(request.model, messages)
+        .stop(params.stop.clone());
+    if stream {
+        builder = builder.stream(true);
+    }
+    if let Some(temperature) = params.temperature {
+        builder = builder.temperature(temperature);
+    }
+    if let Some(top_p) = params.top_p {
+        builder = builder.top_p(top_p);
+    }
+    if let Some(max_tokens) = params.max_tokens {
+        builder = builder.max_tokens(max_tokens);
+    }
+    if let Some(presence_penalty) = params.presence_penalty {
+        builder = builder.presence_penalty(presence_penalty);
+    }
+    if let Some(frequency_penalty) = params.frequency_penalty {
+        builder = builder.frequency_penalty(frequency_penalty);
+    }
+
+    builder.build().map_err(|e| BackendError::Permanent(e.to_string()))
+}
+
+/// Removes the first complete line from the buffer, it is decoded only once all of its bytes have arrived.
+fn take_line(buffer: &mut Vec<u8>) -> Option<String> {
+    let end = buffer.iter().position(|&byte| byte == b'\n')?;
+    let line = buffer.drain(..=end).collect::<Vec<u8>>();
+    Some(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Extracts the new text from a line of the completion stream,
+/// `None` for lines without data, chunks without text and the final `[DONE]`.
+fn parse_event(line: &str) -> Result<Option<String>, serde_json::Error> {
+    let Some(data) = line.strip_prefix("data:") else { return Ok(None); };
+    let data = data.trim();
+    if data == "[DONE]" {
+        return Ok(None);
+    }
+
+    let delta = serde_json::from_str::<ChatCompletionDelta>(data)?;
+    Ok(delta.choices.into_iter().next().and_then(|choice| choice.delta.content))
+}
+
+fn to_openai_message(message: &CompletionMessage) -> ChatCompletionMessage {
+    let role = match message.role {
+        Role::User => ChatCompletionMessageRole::User,
+        Role::Assistant => ChatCompletionMessageRole::Assistant,
+    };
+
+    ChatCompletionMessage {
+        role,
+        name: message.name.clone(),
+        content: message.content.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_event() {
+        let chunk = r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1684000000,"model":"gpt-3.5-turbo-0301","choices":[{"index":0,"delta":{"content":"Hel"},"finish_reason":null}]}"#;
+        let first = r#"data: {"id":"chatcmpl-1","object":"chat.completion.chunk","created":1684000000,"model":"gpt-3.5-turbo-0301","choices":[{"index":0,"delta":{"role":"assistant"},"finish_reason":null}]}"#;
+
+        assert_eq!(parse_event(chunk).unwrap(), Some("Hel".to_string()));
+        assert_eq!(parse_event(first).unwrap(), None);
+        assert_eq!(parse_event("data: [DONE]").unwrap(), None);
+        assert_eq!(parse_event("").unwrap(), None);
+        assert!(parse_event("data: {").is_err());
+    }
+
+    #[test]
+    fn test_take_line_keeps_split_characters() {
+        let line = "data: привет 👋\n".as_bytes();
+        let (head, tail) = line.split_at(9);
+        let mut buffer = head.to_vec();
+
+        assert_eq!(take_line(&mut buffer), None);
+        buffer.extend_from_slice(tail);
+        buffer.extend_from_slice(b"data: [DO");
+        assert_eq!(take_line(&mut buffer), Some("data: привет 👋\n".to_string()));
+        assert_eq!(take_line(&mut buffer), None);
+        assert_eq!(buffer, b"data: [DO");
+    }
+
+    fn api_error(code: &str, message: &str) -> Option<OpenAiError> {
+        Some(OpenAiError {
+            message: message.to_string(),
+            error_type: "error".to_string(),
+            param: None,
+            code: Some(code.to_string()),
+        })
+    }
+
+    #[test]
+    fn test_classify() {
+        let retry_after = Some(Duration::from_secs(20));
+        assert!(matches!(
+            classify(StatusCode::TOO_MANY_REQUESTS, api_error("rate_limit_exceeded", "slow down"), retry_after),
+            BackendError::RateLimited { retry_after: Some(after), .. } if after == Duration::from_secs(20)
+        ));
+        assert!(matches!(classify(StatusCode::TOO_MANY_REQUESTS, api_error("insufficient_quota", "pay up"), None), BackendError::Permanent(_)));
+        assert!(matches!(classify(StatusCode::BAD_GATEWAY, None, None), BackendError::Transient(_)));
+        assert!(matches!(classify(StatusCode::UNAUTHORIZED, api_error("invalid_api_key", "bad key"), None), BackendError::Permanent(_)));
+        assert!(matches!(classify(StatusCode::BAD_REQUEST, api_error("context_length_exceeded", "too long"), None), BackendError::Permanent(_)));
+    }
+}
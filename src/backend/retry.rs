@@ -0,0 +1,245 @@
+use std::time::Duration;
+use async_trait::async_trait;
+use log::warn;
+use rand::Rng;
+use tokio::sync::mpsc::{self, UnboundedSender};
+use tokio::time::sleep;
+use crate::backend::{BackendError, CompletionBackend, CompletionRequest};
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// retries after the first attempt
+    pub max_retries: u32,
+    /// backoff before the first retry, doubled for every next one
+    pub base_delay: Duration,
+    /// longest backoff, a rate limit asking to wait longer is not retried
+    pub max_delay: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_retries: 4,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Delay before the retry following the failed `attempt`, `None` if the error is not worth retrying.
+    fn delay(&self, error: &BackendError, attempt: u32) -> Option<Duration> {
+        if attempt >= self.max_retries {
+            return None;
+        }
+
+        match error {
+            BackendError::RateLimited { retry_after: Some(retry_after), .. } => {
+                (*retry_after <= self.max_delay).then(|| *retry_after + self.jitter(self.base_delay))
+            }
+            BackendError::RateLimited { retry_after: None, .. } | BackendError::Transient(_) => Some(self.backoff(attempt)),
+            BackendError::Permanent(_) => None,
+        }
+    }
+
+    /// Exponential backoff with a random half of it as jitter, so that instances don't retry in lockstep.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay.saturating_mul(2u32.saturating_pow(attempt)).min(self.max_delay);
+        backoff / 2 + self.jitter(backoff / 2)
+    }
+
+    fn jitter(&self, max: Duration) -> Duration {
+        max.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+}
+
+/// Repeats failed requests of the inner backend according to the policy.
+pub struct RetryingBackend<B: CompletionBackend> {
+    inner: B,
+    policy: RetryPolicy,
+}
+
+impl<B: CompletionBackend> RetryingBackend<B> {
+    pub fn new(inner: B, policy: RetryPolicy) -> Self {
+        Self { inner, policy }
+    }
+}
+
+#[async_trait]
+impl<B: CompletionBackend> CompletionBackend for RetryingBackend<B> {
+    async fn complete(&self, request: CompletionRequest<'_>) -> Result<String, BackendError> {
+        let mut attempt = 0;
+        loop {
+            let delay = match self.inner.complete(request).await {
+                Ok(text) => return Ok(text),
+                Err(e) => match self.policy.delay(&e, attempt) {
+                    Some(delay) => {
+                        warn!("completion failed, retrying in {:?}: {}", delay, e);
+                        delay
+                    }
+                    None => return Err(e),
+                },
+            };
+
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+
+    /// A stream which has already sent some text is not retried, the receiver can't take it back.
+    async fn complete_stream(&self, request: CompletionRequest<'_>, deltas: UnboundedSender<String>) -> Result<String, BackendError> {
+        let mut attempt = 0;
+        loop {
+            let (attempt_deltas, mut receiver) = mpsc::unbounded_channel();
+            let mut streamed = false;
+            let forward = async {
+                while let Some(delta) = receiver.recv().await {
+                    streamed = true;
+                    let _ = deltas.send(delta);
+                }
+            };
+            let (result, _) = tokio::join!(self.inner.complete_stream(request, attempt_deltas), forward);
+
+            let delay = match result {
+                Ok(text) => return Ok(text),
+                Err(e) if streamed => return Err(e),
+                Err(e) => match self.policy.delay(&e, attempt) {
+                    Some(delay) => {
+                        warn!("completion failed, retrying in {:?}: {}", delay, e);
+                        delay
+                    }
+                    None => return Err(e),
+                },
+            };
+
+            sleep(delay).await;
+            attempt += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use crate::backend::CompletionParams;
+    use super::*;
+
+    /// Fails with the queued errors first, then succeeds.
+    struct FlakyBackend {
+        errors: Mutex<Vec<BackendError>>,
+        attempts: Mutex<u32>,
+        partial: Option<String>,
+    }
+
+    impl FlakyBackend {
+        fn new(mut errors: Vec<BackendError>) -> Self {
+            errors.reverse();
+            Self { errors: Mutex::new(errors), attempts: Mutex::new(0), partial: None }
+        }
+
+        fn attempts(&self) -> u32 {
+            *self.attempts.lock().unwrap()
+        }
+    }
+
+    #[async_trait]
+    impl CompletionBackend for FlakyBackend {
+        async fn complete(&self, _request: CompletionRequest<'_>) -> Result<String, BackendError> {
+            *self.attempts.lock().unwrap() += 1;
+            match self.errors.lock().unwrap().pop() {
+                Some(error) => Err(error),
+                None => Ok("done".to_string()),
+            }
+        }
+
+        async fn complete_stream(&self, request: CompletionRequest<'_>, deltas: UnboundedSender<String>) -> Result<String, BackendError> {
+            if let Some(partial) = &self.partial {
+                let _ = deltas.send(partial.clone());
+            }
+            let text = self.complete(request).await?;
+            let _ = deltas.send(text.clone());
+            Ok(text)
+        }
+    }
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 2,
+            base_delay: Duration::ZERO,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    async fn complete<B: CompletionBackend>(backend: &B) -> Result<String, BackendError> {
+        let params = CompletionParams::default();
+        backend.complete(CompletionRequest { model: "model", prompt: "prompt", summary: None, messages: &[], params: &params }).await
+    }
+
+    #[tokio::test]
+    async fn test_transient_errors_are_retried() {
+        let backend = RetryingBackend::new(FlakyBackend::new(vec![
+            BackendError::Transient("502".to_string()),
+            BackendError::RateLimited { message: "429".to_string(), retry_after: None },
+        ]), policy());
+
+        assert_eq!(complete(&backend).await.unwrap(), "done");
+        assert_eq!(backend.inner.attempts(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_retries_are_limited() {
+        let errors = (0..3).map(|_| BackendError::Transient("503".to_string())).collect();
+        let backend = RetryingBackend::new(FlakyBackend::new(errors), policy());
+
+        assert!(complete(&backend).await.is_err());
+        assert_eq!(backend.inner.attempts(), 3);
+    }
+
+    #[tokio::test]
+    async fn test_permanent_errors_are_not_retried() {
+        let backend = RetryingBackend::new(FlakyBackend::new(vec![BackendError::Permanent("bad key".to_string())]), policy());
+
+        let error = complete(&backend).await.unwrap_err();
+
+        assert!(matches!(error, BackendError::Permanent(_)));
+        assert_eq!(backend.inner.attempts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_long_retry_after_is_not_waited_for() {
+        let retry_after = Some(Duration::from_secs(60));
+        let backend = RetryingBackend::new(FlakyBackend::new(vec![BackendError::RateLimited { message: "429".to_string(), retry_after }]), policy());
+
+        assert!(complete(&backend).await.is_err());
+        assert_eq!(backend.inner.attempts(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_started_stream_is_not_retried() {
+        let mut inner = FlakyBackend::new(vec![BackendError::Transient("reset".to_string())]);
+        inner.partial = Some("Hel".to_string());
+        let backend = RetryingBackend::new(inner, policy());
+        let params = CompletionParams::default();
+        let request = CompletionRequest { model: "model", prompt: "prompt", summary: None, messages: &[], params: &params };
+        let (deltas, mut receiver) = mpsc::unbounded_channel();
+
+        assert!(backend.complete_stream(request, deltas).await.is_err());
+        assert_eq!(receiver.recv().await, Some("Hel".to_string()));
+        assert_eq!(backend.inner.attempts(), 1);
+    }
+
+    #[test]
+    fn test_backoff_grows_up_to_max_delay() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+        };
+
+        let first = policy.backoff(0);
+        assert!(first >= Duration::from_millis(500) && first <= Duration::from_secs(1));
+        let third = policy.backoff(2);
+        assert!(third >= Duration::from_secs(2) && third <= Duration::from_secs(4));
+        assert!(policy.backoff(9) <= Duration::from_secs(30));
+    }
+}
@@ -1,7 +1,8 @@
 use std::fmt::Debug;
-use std::error::Error;
 use std::collections::HashMap;
 use teloxide::prelude::*;
+use teloxide::types::{UpdateKind, User};
+use thiserror::Error;
 
 #[derive(Debug)]
 #[derive(Clone)]
@@ -12,13 +13,49 @@ pub struct ChatMember {
     pub name: Option<String>,
 }
 
-impl ChatMember {
-    pub fn try_from(_update: &Update) -> Result<ChatMember, Box<dyn Error>> { // TODO: change error type, use tryFrom trait
-        todo!()
+impl From<&User> for ChatMember {
+    fn from(user: &User) -> Self {
+        Self {
+            id: user.id,
+            name: Some(user.full_name()),
+        }
+    }
+}
+
+impl TryFrom<&Update> for ChatMember {
+    type Error = ChatMemberError;
+
+    fn try_from(update: &Update) -> Result<Self, Self::Error> {
+        get_user(update).map(ChatMember::from)
+    }
+}
+
+/// Returns the chat member the update is about.
+pub fn get_user(update: &Update) -> Result<&User, ChatMemberError> {
+    match &update.kind {
+        UpdateKind::Message(m) | UpdateKind::EditedMessage(m) => m.from().ok_or(ChatMemberError::NoSender),
+        UpdateKind::CallbackQuery(q) => Ok(&q.from),
+        UpdateKind::ChatMember(m) => Ok(&m.new_chat_member.user),
+        UpdateKind::MyChatMember(m) => Ok(&m.from),
+        UpdateKind::PollAnswer(a) => Ok(&a.user),
+        UpdateKind::ChatJoinRequest(r) => Ok(&r.from),
+        _ => Err(ChatMemberError::UnsupportedKind),
     }
 }
 
+#[derive(Debug, PartialEq, Error)]
+pub enum ChatMemberError {
+    // the update kind does not refer to a chat member
+    #[error("update kind carries no chat member")]
+    UnsupportedKind,
+
+    // the message has no sender, e.g. it was sent by an anonymous admin
+    #[error("message has no sender")]
+    NoSender,
+}
+
 pub struct ChatData {
+    #[allow(dead_code)]
     id: ChatId,
     users: HashMap<UserId, ChatMember>,
 }
@@ -31,6 +68,12 @@ impl ChatData {
         }
     }
 
+    pub fn with_users(chat_id: ChatId, users: Vec<ChatMember>) -> Self {
+        let mut chat_data = Self::new(chat_id);
+        chat_data.users.extend(users.into_iter().map(|user| (user.id, user)));
+        chat_data
+    }
+
     pub fn update_user(&mut self, user: ChatMember) -> ChannelUserUpdateResult {
         let user_id = user.id;
         match self.users.insert(user_id, user)
@@ -62,6 +105,7 @@ pub enum ChannelUserUpdateResult {
 
 #[cfg(test)]
 mod tests {
+    use crate::fixtures::parse_update;
     use super::*;
 
     #[test]
@@ -74,6 +118,83 @@ mod tests {
         assert_eq!(chat_member.name, name);
     }
 
+    #[test]
+    fn test_chat_member_from_message() {
+        let update = parse_update(include_str!("../fixtures/updates/message.json"));
+
+        let member = ChatMember::try_from(&update).unwrap();
+
+        assert_eq!(member, ChatMember { id: UserId(1001), name: Some("Alice Smith".to_string()) });
+    }
+
+    #[test]
+    fn test_chat_member_from_edited_message() {
+        let update = parse_update(include_str!("../fixtures/updates/edited_message.json"));
+
+        let member = ChatMember::try_from(&update).unwrap();
+
+        assert_eq!(member, ChatMember { id: UserId(1001), name: Some("Alice Smith".to_string()) });
+    }
+
+    #[test]
+    fn test_chat_member_from_callback_query() {
+        let update = parse_update(include_str!("../fixtures/updates/callback_query.json"));
+
+        let member = ChatMember::try_from(&update).unwrap();
+
+        assert_eq!(member, ChatMember { id: UserId(1002), name: Some("Bob".to_string()) });
+    }
+
+    #[test]
+    fn test_chat_member_from_chat_member() {
+        let update = parse_update(include_str!("../fixtures/updates/chat_member.json"));
+
+        let member = ChatMember::try_from(&update).unwrap();
+
+        assert_eq!(member, ChatMember { id: UserId(1003), name: Some("Carol".to_string()) });
+    }
+
+    #[test]
+    fn test_chat_member_from_my_chat_member() {
+        let update = parse_update(include_str!("../fixtures/updates/my_chat_member.json"));
+
+        let member = ChatMember::try_from(&update).unwrap();
+
+        assert_eq!(member, ChatMember { id: UserId(1001), name: Some("Alice Smith".to_string()) });
+    }
+
+    #[test]
+    fn test_chat_member_from_poll_answer() {
+        let update = parse_update(include_str!("../fixtures/updates/poll_answer.json"));
+
+        let member = ChatMember::try_from(&update).unwrap();
+
+        assert_eq!(member, ChatMember { id: UserId(1002), name: Some("Bob".to_string()) });
+    }
+
+    #[test]
+    fn test_chat_member_from_chat_join_request() {
+        let update = parse_update(include_str!("../fixtures/updates/chat_join_request.json"));
+
+        let member = ChatMember::try_from(&update).unwrap();
+
+        assert_eq!(member, ChatMember { id: UserId(1004), name: Some("Dave".to_string()) });
+    }
+
+    #[test]
+    fn test_chat_member_from_anonymous_message() {
+        let update = parse_update(include_str!("../fixtures/updates/anonymous_message.json"));
+
+        assert_eq!(ChatMember::try_from(&update), Err(ChatMemberError::NoSender));
+    }
+
+    #[test]
+    fn test_chat_member_from_poll() {
+        let update = parse_update(include_str!("../fixtures/updates/poll.json"));
+
+        assert_eq!(ChatMember::try_from(&update), Err(ChatMemberError::UnsupportedKind));
+    }
+
     #[test]
     fn test_chat_data_new() {
         let id = ChatId(1);
@@ -83,6 +204,16 @@ mod tests {
         assert_eq!(chat_data.users.len(), 0);
     }
 
+    #[test]
+    fn test_chat_data_with_users() {
+        let id = ChatId(1);
+        let user = ChatMember { id: UserId(2), name: Some("Bob".to_string()) };
+        let mut chat_data = ChatData::with_users(id, vec![user.clone()]);
+
+        assert_eq!(chat_data.users.len(), 1);
+        assert_eq!(chat_data.update_user(user), ChannelUserUpdateResult::NoChanges);
+    }
+
     #[test]
     fn test_chat_data_update_new_entry() {
         let id = ChatId(1);
@@ -0,0 +1,330 @@
+/// Telegram limit for a text message, in UTF-16 code units.
+pub const MESSAGE_LIMIT: usize = 4096;
+
+const FENCE: &str = "```";
+
+/// Splits the text into parts of at most `limit` UTF-16 code units, preferring paragraph, then
+/// sentence, then word boundaries. Code blocks are kept whole when possible, otherwise every part
+/// is re-fenced. Inline code and bold/underline markers are never split unless a single word is too long.
+pub fn split_message(text: &str, limit: usize) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for piece in split_pieces(text, Level::Paragraph, limit) {
+        if len(&current) + len(&piece) > limit && !current.trim().is_empty() {
+            chunks.push(current.trim().to_string());
+            current.clear();
+        }
+        current.push_str(&piece);
+    }
+
+    if !current.trim().is_empty() {
+        chunks.push(current.trim().to_string());
+    }
+
+    chunks
+}
+
+#[derive(Clone, Copy)]
+enum Level {
+    Paragraph,
+    Sentence,
+    Word,
+    Char,
+}
+
+impl Level {
+    fn next(self) -> Self {
+        match self {
+            Level::Paragraph => Level::Sentence,
+            Level::Sentence => Level::Word,
+            Level::Word | Level::Char => Level::Char,
+        }
+    }
+}
+
+/// Returns pieces no longer than `limit` which concatenated give back the text,
+/// except for oversized code blocks which get extra fences.
+fn split_pieces(text: &str, level: Level, limit: usize) -> Vec<String> {
+    let parts = match level {
+        Level::Paragraph => split_paragraphs(text),
+        Level::Sentence => join_unbalanced(text, split_sentences(text)),
+        Level::Word => join_unbalanced(text, split_words(text)),
+        Level::Char => return split_chars(text, limit),
+    };
+
+    let mut pieces = Vec::new();
+    for part in parts {
+        if len(part) <= limit {
+            pieces.push(part.to_string());
+        } else if is_code_block(part) {
+            pieces.extend(split_code_block(part, limit));
+        } else {
+            pieces.extend(split_pieces(part, level.next(), limit));
+        }
+    }
+
+    pieces
+}
+
+/// Paragraphs are separated by blank lines, a fenced code block is always a paragraph of its own.
+fn split_paragraphs(text: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut pos = 0;
+    let mut in_code = false;
+
+    for line in text.split_inclusive('\n') {
+        let is_fence = line.trim_start().starts_with(FENCE);
+        if is_fence && !in_code {
+            if pos > start {
+                parts.push(&text[start..pos]);
+                start = pos;
+            }
+            in_code = true;
+            pos += line.len();
+        } else if (is_fence && in_code) || (!in_code && line.trim().is_empty()) {
+            pos += line.len();
+            parts.push(&text[start..pos]);
+            start = pos;
+            in_code = false;
+        } else {
+            pos += line.len();
+        }
+    }
+
+    if pos > start {
+        parts.push(&text[start..pos]);
+    }
+
+    parts
+}
+
+fn split_sentences(text: &str) -> Vec<&str> {
+    split_after(text, |prev, c| prev == '\n' || (c.is_whitespace() && matches!(prev, '.' | '!' | '?' | '…')))
+}
+
+fn split_words(text: &str) -> Vec<&str> {
+    split_after(text, |prev, c| prev.is_whitespace() && !c.is_whitespace())
+}
+
+/// Cuts the text before every char for which `is_boundary(previous char, char)` holds,
+/// a boundary followed by whitespace is moved past the whitespace run.
+fn split_after(text: &str, is_boundary: impl Fn(char, char) -> bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut prev: Option<char> = None;
+    let mut pending_cut = false;
+
+    for (i, c) in text.char_indices() {
+        if pending_cut && !c.is_whitespace() {
+            parts.push(&text[start..i]);
+            start = i;
+            pending_cut = false;
+        }
+        if let Some(prev) = prev {
+            if is_boundary(prev, c) {
+                if c.is_whitespace() {
+                    pending_cut = true;
+                } else if i > start {
+                    parts.push(&text[start..i]);
+                    start = i;
+                }
+            }
+        }
+        prev = Some(c);
+    }
+
+    if start < text.len() {
+        parts.push(&text[start..]);
+    }
+
+    parts
+}
+
+fn split_chars(text: &str, limit: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+
+    for c in text.chars() {
+        if len(&current) + c.len_utf16() > limit {
+            pieces.push(std::mem::take(&mut current));
+        }
+        current.push(c);
+    }
+
+    if !current.is_empty() {
+        pieces.push(current);
+    }
+
+    pieces
+}
+
+/// Merges neighbouring parts while inline formatting markers are left open.
+/// The parts must be consecutive slices covering the whole text.
+fn join_unbalanced<'a>(text: &'a str, parts: Vec<&str>) -> Vec<&'a str> {
+    let mut joined = Vec::new();
+    let mut start = 0;
+    let mut end = 0;
+
+    for part in parts {
+        end += part.len();
+        if is_balanced(&text[start..end]) {
+            joined.push(&text[start..end]);
+            start = end;
+        }
+    }
+
+    if start < end {
+        joined.push(&text[start..end]);
+    }
+
+    joined
+}
+
+fn is_balanced(text: &str) -> bool {
+    text.matches(FENCE).count() % 2 == 0
+        && text.replace(FENCE, "").matches('`').count() % 2 == 0
+        && text.matches("**").count() % 2 == 0
+        && text.matches("__").count() % 2 == 0
+}
+
+fn is_code_block(text: &str) -> bool {
+    text.trim_start().starts_with(FENCE)
+}
+
+/// Splits an oversized code block by lines, closing and reopening the fence in every part.
+fn split_code_block(block: &str, limit: usize) -> Vec<String> {
+    let block = block.trim_end();
+    let (opening, body) = block.split_once('\n').unwrap_or((block, ""));
+    let opening = format!("{}\n", opening.trim());
+    let body = body.strip_suffix(FENCE).unwrap_or(body);
+
+    // the longest line must fit together with both fences and line breaks
+    let overhead = len(&opening) + len(FENCE) + 2;
+    if overhead >= limit {
+        return split_chars(block, limit);
+    }
+    let room = limit - overhead;
+
+    let mut pieces = Vec::new();
+    let mut current = String::new();
+    let lines = body.split_inclusive('\n').flat_map(|line| split_chars(line, room));
+    for line in lines {
+        if !current.is_empty() && len(&fence(&opening, &(current.clone() + &line))) > limit {
+            pieces.push(fence(&opening, &current));
+            current.clear();
+        }
+        current.push_str(&line);
+    }
+    pieces.push(fence(&opening, &current));
+
+    pieces
+}
+
+fn fence(opening: &str, body: &str) -> String {
+    let newline = if body.ends_with('\n') { "" } else { "\n" };
+    format!("{}{}{}{}\n", opening, body, newline, FENCE)
+}
+
+fn len(text: &str) -> usize {
+    text.encode_utf16().count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_short_message_is_not_split() {
+        assert_eq!(split_message("Hello!", 20), vec!["Hello!"]);
+    }
+
+    #[test]
+    fn test_empty_message_gives_no_parts() {
+        assert!(split_message(" \n\n ", 20).is_empty());
+    }
+
+    #[test]
+    fn test_split_on_paragraphs() {
+        let text = "First paragraph.\n\nSecond paragraph.\n\nThird one.";
+
+        assert_eq!(split_message(text, 40), vec!["First paragraph.\n\nSecond paragraph.", "Third one."]);
+    }
+
+    #[test]
+    fn test_split_on_sentences() {
+        let text = "One sentence here. Another sentence! And a question?";
+
+        assert_eq!(split_message(text, 25), vec!["One sentence here.", "Another sentence!", "And a question?"]);
+    }
+
+    #[test]
+    fn test_split_on_words() {
+        let text = "a very long sentence without any punctuation";
+
+        let parts = split_message(text, 12);
+
+        assert_eq!(parts, vec!["a very long", "sentence", "without any", "punctuation"]);
+    }
+
+    #[test]
+    fn test_split_long_word() {
+        assert_eq!(split_message("abcdefghij", 4), vec!["abcd", "efgh", "ij"]);
+    }
+
+    #[test]
+    fn test_limit_is_in_utf16_units() {
+        let parts = split_message("😀😀😀", 4);
+
+        assert_eq!(parts, vec!["😀😀", "😀"]);
+    }
+
+    #[test]
+    fn test_code_block_is_kept_whole() {
+        let text = "Intro text.\n\n```rust\nfn main() {\n\n    println!(\"hi\");\n}\n```\n\nOutro.";
+
+        let parts = split_message(text, 50);
+
+        assert_eq!(parts, vec!["Intro text.", "```rust\nfn main() {\n\n    println!(\"hi\");\n}\n```", "Outro."]);
+    }
+
+    #[test]
+    fn test_long_code_block_is_refenced() {
+        let text = "```\nline one\nline two\nline three\n```\nDone.";
+
+        let parts = split_message(text, 26);
+
+        assert_eq!(parts, vec!["```\nline one\nline two\n```", "```\nline three\n```\nDone."]);
+        assert!(parts.iter().all(|part| len(part) <= 26));
+    }
+
+    #[test]
+    fn test_inline_code_is_not_split() {
+        let text = "Run `cargo build --release` now";
+
+        let parts = split_message(text, 28);
+
+        assert_eq!(parts, vec!["Run `cargo build --release`", "now"]);
+    }
+
+    #[test]
+    fn test_bold_is_not_split() {
+        let text = "Note. **Very important. Really.** Done.";
+
+        let parts = split_message(text, 30);
+
+        assert_eq!(parts, vec!["Note.", "**Very important. Really.**", "Done."]);
+    }
+
+    #[test]
+    fn test_parts_fit_telegram_limit() {
+        let text = "Lorem ipsum dolor sit amet. ".repeat(500);
+
+        let parts = split_message(&text, MESSAGE_LIMIT);
+
+        assert_eq!(parts.len(), 4);
+        assert!(parts.iter().all(|part| len(part) <= MESSAGE_LIMIT));
+        assert_eq!(parts.join(" "), text.trim());
+    }
+}
@@ -0,0 +1,130 @@
+use teloxide::types::{ChatId, Message};
+use crate::backend::CompletionBackend;
+use crate::db::{ChatKey, Db};
+use crate::error::Result;
+use crate::gpt::{self, Gpt};
+
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum Command {
+    Prompt(Option<String>),
+    Model(Option<String>),
+    Capacity(Option<String>),
+    Pause,
+    Resume,
+    Status,
+    Reset,
+}
+
+impl Command {
+    /// Parses a bot command from the message text, `None` if the message is not a command for this bot.
+    pub fn parse(text: &str, bot_username: &str) -> Option<Self> {
+        let text = text.trim();
+        let text = text.strip_prefix('/')?;
+        let (name, args) = match text.split_once(char::is_whitespace) {
+            Some((name, args)) => (name, Some(args.trim().to_string()).filter(|args| !args.is_empty())),
+            None => (text, None),
+        };
+
+        let name = match name.split_once('@') {
+            Some((name, username)) if username.eq_ignore_ascii_case(bot_username) => name,
+            Some(_) => return None,
+            None => name,
+        };
+
+        match name {
+            "prompt" => Some(Command::Prompt(args)),
+            "model" => Some(Command::Model(args)),
+            "capacity" => Some(Command::Capacity(args)),
+            "pause" => Some(Command::Pause),
+            "resume" => Some(Command::Resume),
+            "status" => Some(Command::Status),
+            "reset" => Some(Command::Reset),
+            _ => None,
+        }
+    }
+
+    pub fn from_message(message: &Message, bot_username: &str) -> Option<Self> {
+        Self::parse(message.text()?, bot_username)
+    }
+
+    /// Applies the command to the settings of the chat and its live `Gpt`, returns the reply for the chat.
+    pub async fn execute<B: CompletionBackend>(self, db: &Db, chat_id: ChatId, gpt: &mut Gpt<B>) -> Result<String> {
+        let reply = match self {
+            Command::Prompt(None) => format!("Current prompt:\n{}", gpt.prompt()),
+            Command::Prompt(Some(prompt)) => {
+                db.write_chat_value(chat_id, ChatKey::Prompt, Some(prompt.clone())).await?;
+                gpt.set_prompt(prompt);
+                "Prompt has been updated".to_string()
+            }
+            Command::Model(None) => format!("Current model: {}", gpt.model()),
+            Command::Model(Some(model)) => {
+                db.write_chat_value(chat_id, ChatKey::Model, Some(model.clone())).await?;
+                let reply = format!("Model has been set to {}", model);
+                gpt.set_model(model);
+                reply
+            }
+            Command::Capacity(None) => format!("Current history capacity: {}", gpt.capacity()),
+            Command::Capacity(Some(capacity)) => {
+                let capacity = match capacity.parse::<usize>().map_err(|e| e.to_string()).and_then(gpt::validate_history_capacity) {
+                    Ok(capacity) => capacity,
+                    Err(e) => return Ok(format!("Invalid capacity: {}", e)),
+                };
+                db.write_chat_value(chat_id, ChatKey::HistoryCapacity, Some(capacity.to_string())).await?;
+                gpt.reload_capacity(db, chat_id).await?;
+                format!("History capacity has been set to {}", capacity)
+            }
+            Command::Pause => {
+                db.write_chat_value(chat_id, ChatKey::Paused, Some(true.to_string())).await?;
+                "Paused, I will stay silent until /resume".to_string()
+            }
+            Command::Resume => {
+                db.write_chat_value(chat_id, ChatKey::Paused, Some(false.to_string())).await?;
+                "Resumed".to_string()
+            }
+            Command::Status => {
+                let paused = db.read_chat_value::<bool>(chat_id, ChatKey::Paused).await?.unwrap_or(false);
+                let summary = gpt.summary().map_or(0, |summary| summary.chars().count());
+                format!("Model: {}\nHistory: {}/{}\nSummary: {} chars\nPaused: {}", gpt.model(), gpt.history_len(), gpt.capacity(), summary, paused)
+            }
+            Command::Reset => {
+                gpt.reset();
+                db.write_chat_value(chat_id, ChatKey::Summary, None).await?;
+                "History has been cleared".to_string()
+            }
+        };
+
+        Ok(reply)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_simple_command() {
+        assert_eq!(Command::parse("/status", "pipe_bot"), Some(Command::Status));
+        assert_eq!(Command::parse("  /pause  ", "pipe_bot"), Some(Command::Pause));
+    }
+
+    #[test]
+    fn test_parse_command_with_args() {
+        assert_eq!(Command::parse("/model gpt-4", "pipe_bot"), Some(Command::Model(Some("gpt-4".to_string()))));
+        assert_eq!(Command::parse("/prompt You are\na bot", "pipe_bot"), Some(Command::Prompt(Some("You are\na bot".to_string()))));
+        assert_eq!(Command::parse("/capacity   ", "pipe_bot"), Some(Command::Capacity(None)));
+    }
+
+    #[test]
+    fn test_parse_addressed_command() {
+        assert_eq!(Command::parse("/reset@Pipe_Bot", "pipe_bot"), Some(Command::Reset));
+        assert_eq!(Command::parse("/reset@other_bot", "pipe_bot"), None);
+    }
+
+    #[test]
+    fn test_parse_not_a_command() {
+        assert_eq!(Command::parse("status", "pipe_bot"), None);
+        assert_eq!(Command::parse("/start", "pipe_bot"), None);
+        assert_eq!(Command::parse("/", "pipe_bot"), None);
+    }
+}
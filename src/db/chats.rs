@@ -0,0 +1,51 @@
+use sqlx::{Row, SqlitePool};
+use sqlx::sqlite::SqliteQueryResult;
+use teloxide::prelude::ChatId;
+
+/// Chats the bot takes part in. A NULL setting falls back to the value in the conf table.
+pub struct Chat;
+
+impl Chat {
+    pub async fn insert(pool: &SqlitePool, chat_id: ChatId) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("INSERT OR IGNORE INTO chats (chat_id) VALUES (?)")
+            .bind(chat_id.0)
+            .execute(pool)
+            .await
+    }
+
+    pub async fn delete(pool: &SqlitePool, chat_id: ChatId) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM chats WHERE chat_id = ?")
+            .bind(chat_id.0)
+            .execute(pool)
+            .await
+    }
+
+    pub async fn select_ids(pool: &SqlitePool) -> Result<Vec<ChatId>, sqlx::Error> {
+        let rows = sqlx::query("SELECT chat_id FROM chats ORDER BY chat_id")
+            .fetch_all(pool)
+            .await?;
+
+        rows.iter().map(|row| row.try_get::<i64, usize>(0).map(ChatId)).collect()
+    }
+
+    /// `column` must be one of the setting columns, it can't be bound as a parameter.
+    pub async fn select_value(pool: &SqlitePool, chat_id: ChatId, column: &'static str) -> Result<Option<String>, sqlx::Error> {
+        let row = sqlx::query(&format!("SELECT {} FROM chats WHERE chat_id = ?", column))
+            .bind(chat_id.0)
+            .fetch_optional(pool)
+            .await?;
+
+        match row {
+            Some(row) => row.try_get::<Option<String>, usize>(0),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn update_value(pool: &SqlitePool, chat_id: ChatId, column: &'static str, value: Option<String>) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query(&format!("UPDATE chats SET {} = ? WHERE chat_id = ?", column))
+            .bind(value)
+            .bind(chat_id.0)
+            .execute(pool)
+            .await
+    }
+}
@@ -0,0 +1,44 @@
+use sqlx::SqlitePool;
+use sqlx::sqlite::SqliteQueryResult;
+use teloxide::types::Update;
+
+/// An update which failed processing, kept for inspection.
+pub struct DeadLetter {
+    update_id: Option<i32>,
+    stage: String,
+    error: String,
+    raw: Option<String>,
+}
+
+impl DeadLetter {
+    pub fn new(update: Option<&Update>, stage: &str, error: &str) -> Self {
+        Self {
+            update_id: update.map(|update| update.id),
+            stage: stage.to_string(),
+            error: error.to_string(),
+            raw: update.and_then(|update| serde_json::to_string(update).ok()),
+        }
+    }
+
+    pub async fn insert(&self, pool: &SqlitePool) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("INSERT INTO dead_letters (update_id, stage, error, raw) VALUES (?, ?, ?, ?)")
+            .bind(self.update_id)
+            .bind(&self.stage)
+            .bind(&self.error)
+            .bind(&self.raw)
+            .execute(pool)
+            .await
+    }
+
+    /// Update ids, stages and errors of the dead letters, oldest first.
+    #[cfg(test)]
+    pub async fn select_all(pool: &SqlitePool) -> Result<Vec<(Option<i32>, String, String)>, sqlx::Error> {
+        use sqlx::Row;
+
+        let rows = sqlx::query("SELECT update_id, stage, error FROM dead_letters ORDER BY id")
+            .fetch_all(pool)
+            .await?;
+
+        rows.iter().map(|row| Ok((row.try_get(0)?, row.try_get(1)?, row.try_get(2)?))).collect()
+    }
+}
@@ -0,0 +1,205 @@
+use std::time::Duration;
+use sqlx::{Executor, Row, Sqlite, SqlitePool};
+use sqlx::sqlite::SqliteQueryResult;
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::types::Update;
+
+/// Processing state of an update in the inbox.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum InboxState {
+    /// committed together with the offset, not handled yet
+    Received,
+    /// claimed by its chat worker, which may have responded to it already
+    Processing,
+    Processed,
+    /// moved to dead letters
+    Failed,
+}
+
+impl InboxState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            InboxState::Received => "received",
+            InboxState::Processing => "processing",
+            InboxState::Processed => "processed",
+            InboxState::Failed => "failed",
+        }
+    }
+}
+
+/// Updates taken from Telegram. Processed ones are pruned after a while, failed ones are kept.
+pub struct Inbox {
+    update_id: i32,
+    chat_id: Option<i64>,
+    raw: String,
+}
+
+impl Inbox {
+    pub fn from(update: &Update) -> Result<Self, serde_json::Error> {
+        Ok(Self {
+            update_id: update.id,
+            chat_id: update.chat_id().map(|chat_id| chat_id.0),
+            raw: serde_json::to_string(update)?,
+        })
+    }
+
+    /// An update received again keeps its state, so it is not handled twice.
+    /// Returns `false` if the update is in the inbox already.
+    pub async fn insert<'e, E: Executor<'e, Database = Sqlite>>(&self, executor: E) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("INSERT OR IGNORE INTO inbox (update_id, chat_id, state, raw) VALUES (?, ?, ?, ?)")
+            .bind(self.update_id)
+            .bind(self.chat_id)
+            .bind(InboxState::Received.as_str())
+            .bind(&self.raw)
+            .execute(executor)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    /// Only unfinished updates change state, a failed update stays failed once its batch is processed.
+    pub async fn update_state(pool: &SqlitePool, update_id: i32, state: InboxState, error: Option<&str>) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query(
+            "UPDATE inbox SET state = ?, error = ?, updated_at = CURRENT_TIMESTAMP \
+            WHERE update_id = ? AND state IN (?, ?)")
+            .bind(state.as_str())
+            .bind(error)
+            .bind(update_id)
+            .bind(InboxState::Received.as_str())
+            .bind(InboxState::Processing.as_str())
+            .execute(pool)
+            .await
+    }
+
+    /// Moves a received update to processing, returns `false` if it has been claimed or handled before.
+    pub async fn claim<'e, E: Executor<'e, Database = Sqlite>>(executor: E, update_id: i32) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE inbox SET state = ?, updated_at = CURRENT_TIMESTAMP \
+            WHERE update_id = ? AND state = ?")
+            .bind(InboxState::Processing.as_str())
+            .bind(update_id)
+            .bind(InboxState::Received.as_str())
+            .execute(executor)
+            .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn update_all_states(pool: &SqlitePool, from: InboxState, to: InboxState, error: Option<&str>) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("UPDATE inbox SET state = ?, error = ?, updated_at = CURRENT_TIMESTAMP WHERE state = ?")
+            .bind(to.as_str())
+            .bind(error)
+            .bind(from.as_str())
+            .execute(pool)
+            .await
+    }
+
+    /// Failed updates are kept for inspection.
+    pub async fn delete_processed(pool: &SqlitePool, older_than: Duration) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query("DELETE FROM inbox WHERE state = ? AND updated_at < datetime('now', ?)")
+            .bind(InboxState::Processed.as_str())
+            .bind(format!("-{} seconds", older_than.as_secs()))
+            .execute(pool)
+            .await
+    }
+
+    pub async fn select_received(pool: &SqlitePool) -> Result<Vec<Update>, sqlx::Error> {
+        let rows = sqlx::query("SELECT raw FROM inbox WHERE state = ? ORDER BY update_id")
+            .bind(InboxState::Received.as_str())
+            .fetch_all(pool)
+            .await?;
+
+        rows.iter()
+            .map(|row| {
+                let raw = row.try_get::<String, usize>(0)?;
+                serde_json::from_str(&raw).map_err(|e| sqlx::Error::Decode(Box::new(e)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::{ConfKey, Db};
+    use crate::fixtures::{update, CHAT_ID};
+    use super::*;
+
+    fn ids(updates: Vec<Update>) -> Vec<i32> {
+        updates.iter().map(|update| update.id).collect()
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_committed_with_offset() {
+        let db = Db::in_memory().await;
+        let (first, second) = (update(1, CHAT_ID), update(2, CHAT_ID));
+
+        db.receive_updates(&[&first, &second], Some(3)).await.unwrap();
+
+        assert_eq!(db.read_conf_value::<i32>(ConfKey::Offset).await.unwrap(), Some(3));
+        let received = db.received_updates().await.unwrap();
+        assert_eq!(received[0].kind, first.kind);
+        assert_eq!(ids(received), vec![1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_handled_updates_are_not_resumed() {
+        let db = Db::in_memory().await;
+        db.receive_updates(&[&update(1, CHAT_ID), &update(2, CHAT_ID), &update(3, CHAT_ID)], Some(4)).await.unwrap();
+
+        db.save_dead_letter(Some(&update(2, CHAT_ID)), "executing command", "no such chat").await.unwrap();
+        db.mark_processed(&[1, 2]).await.unwrap();
+        // received again, e.g. after the offset has been reset
+        assert_eq!(db.receive_updates(&[&update(1, CHAT_ID), &update(4, CHAT_ID)], Some(5)).await.unwrap(), vec![4]);
+        assert!(!db.receive_update(&update(3, CHAT_ID)).await.unwrap());
+
+        assert_eq!(ids(db.received_updates().await.unwrap()), vec![3, 4]);
+        assert_eq!(state(&db, 2).await, "failed");
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_claimed_once() {
+        let db = Db::in_memory().await;
+        db.receive_updates(&[&update(1, CHAT_ID), &update(2, CHAT_ID), &update(3, CHAT_ID)], Some(4)).await.unwrap();
+        db.mark_processed(&[2]).await.unwrap();
+
+        assert_eq!(db.claim_updates(&[1, 2, 3, 1]).await.unwrap(), vec![1, 3]);
+        assert_eq!(db.claim_updates(&[1, 3]).await.unwrap(), Vec::<i32>::new());
+    }
+
+    #[tokio::test]
+    async fn test_interrupted_updates_are_not_resumed() {
+        let db = Db::in_memory().await;
+        db.receive_updates(&[&update(1, CHAT_ID), &update(2, CHAT_ID), &update(3, CHAT_ID)], Some(4)).await.unwrap();
+
+        db.claim_updates(&[1, 2]).await.unwrap();
+        db.mark_processed(&[1]).await.unwrap();
+
+        assert_eq!(db.fail_interrupted_updates().await.unwrap(), 1);
+        assert_eq!(ids(db.received_updates().await.unwrap()), vec![3]);
+        assert_eq!(state(&db, 1).await, "processed");
+        assert_eq!(state(&db, 2).await, "failed");
+    }
+
+    #[tokio::test]
+    async fn test_old_processed_updates_are_pruned() {
+        let db = Db::in_memory().await;
+        db.receive_updates(&[&update(1, CHAT_ID), &update(2, CHAT_ID), &update(3, CHAT_ID), &update(4, CHAT_ID)], Some(5)).await.unwrap();
+        db.mark_processed(&[1, 4]).await.unwrap();
+        db.save_dead_letter(Some(&update(2, CHAT_ID)), "executing command", "no such chat").await.unwrap();
+        sqlx::query("UPDATE inbox SET updated_at = datetime('now', '-2 days') WHERE update_id < 4").execute(&db.pool).await.unwrap();
+
+        assert_eq!(db.prune_inbox(Duration::from_secs(24 * 60 * 60)).await.unwrap(), 1);
+
+        let remaining = sqlx::query("SELECT update_id FROM inbox ORDER BY update_id").fetch_all(&db.pool).await.unwrap();
+        assert_eq!(remaining.iter().map(|row| row.get::<i32, usize>(0)).collect::<Vec<_>>(), vec![2, 3, 4]);
+    }
+
+    async fn state(db: &Db, update_id: i32) -> String {
+        sqlx::query("SELECT state FROM inbox WHERE update_id = ?")
+            .bind(update_id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap()
+            .get(0)
+    }
+}
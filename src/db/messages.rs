@@ -1,9 +1,9 @@
 use sqlx::{FromRow, SqlitePool};
 use sqlx::sqlite::SqliteQueryResult;
 use teloxide::prelude::{ChatId, UserId};
-use teloxide::types::{Message as TgMessage, MessageId, Update, UpdateKind};
+use teloxide::types::{FileMeta, ForwardedFrom, Message as TgMessage, MessageId, Poll, Update, UpdateKind};
 
-#[derive(Debug, FromRow)]
+#[derive(Debug, Default, FromRow)]
 pub struct Message {
     update_id: i32,
     chat_id: Option<ChatId>,
@@ -11,84 +11,145 @@ pub struct Message {
     kind: String,
     from_id: Option<UserId>,
     content: Option<String>,
+    caption: Option<String>,
+    media_type: Option<String>,
+    file_id: Option<String>,
+    sticker_emoji: Option<String>,
+    poll_question: Option<String>,
+    /// JSON array of the option texts
+    poll_options: Option<String>,
+    forwarded_from: Option<String>,
+    reply_to_message_id: Option<i32>,
+    /// JSON array of the text or caption entities
+    entities: Option<String>,
     raw: Option<String>,
 }
 
 impl Message {
     pub fn from(update: &Update) -> Self {
-        let (chat_id, message_id, from_id, content) = match &update.kind {
-            UpdateKind::Message(message) => parse_message(&message),
-            UpdateKind::EditedMessage(message) => parse_message(&message),
-            UpdateKind::ChannelPost(message) => parse_message(&message),
-            UpdateKind::EditedChannelPost(message) => parse_message(&message),
-            UpdateKind::InlineQuery(q) => parse_unknown(q),
-            UpdateKind::ChosenInlineResult(r) => parse_unknown(r),
-            UpdateKind::CallbackQuery(q) => parse_unknown(q),
-            UpdateKind::ShippingQuery(q) => parse_unknown(q),
-            UpdateKind::PreCheckoutQuery(q) => parse_unknown(q),
-            UpdateKind::Poll(p) => parse_unknown(p),
-            UpdateKind::PollAnswer(pa) => parse_unknown(pa),
-            UpdateKind::MyChatMember(m) => parse_unknown(m),
-            UpdateKind::ChatMember(m) => parse_unknown(m),
-            UpdateKind::ChatJoinRequest(r) => parse_unknown(r),
-            UpdateKind::Error(e) => parse_unknown(e),
-        };
-
-        Self {
+        let mut message = Self {
             update_id: update.id,
-            chat_id,
-            message_id,
             kind: upd_kind_to_string(&update.kind).to_string(),
-            from_id,
-            content,
-            raw: get_raw(&update),
+            raw: get_raw(update),
+            ..Default::default()
+        };
+
+        match &update.kind {
+            UpdateKind::Message(m)
+            | UpdateKind::EditedMessage(m)
+            | UpdateKind::ChannelPost(m)
+            | UpdateKind::EditedChannelPost(m) => message.parse_message(m),
+            UpdateKind::InlineQuery(q) => {
+                message.from_id = Some(q.from.id);
+                message.content = Some(q.query.clone());
+            }
+            UpdateKind::ChosenInlineResult(r) => {
+                message.from_id = Some(r.from.id);
+                message.content = Some(r.query.clone());
+            }
+            UpdateKind::CallbackQuery(q) => {
+                message.chat_id = q.message.as_ref().map(|m| m.chat.id);
+                message.message_id = q.message.as_ref().map(|m| m.id);
+                message.from_id = Some(q.from.id);
+                message.content = q.data.clone();
+            }
+            UpdateKind::ShippingQuery(q) => message.from_id = Some(q.from.id),
+            UpdateKind::PreCheckoutQuery(q) => message.from_id = Some(q.from.id),
+            UpdateKind::Poll(p) => message.parse_poll(p),
+            UpdateKind::PollAnswer(pa) => {
+                message.from_id = Some(pa.user.id);
+                // the chosen options, empty if the vote was retracted
+                message.content = serde_json::to_string(&pa.option_ids).ok();
+            }
+            UpdateKind::MyChatMember(m) | UpdateKind::ChatMember(m) => {
+                message.chat_id = Some(m.chat.id);
+                message.from_id = Some(m.from.id);
+            }
+            UpdateKind::ChatJoinRequest(r) => {
+                message.chat_id = Some(r.chat.id);
+                message.from_id = Some(r.from.id);
+            }
+            UpdateKind::Error(_) => {}
         }
+
+        message
     }
 
-    pub async fn create_table(pool: &SqlitePool) -> Result<SqliteQueryResult, sqlx::Error> {
-        sqlx::query(
-                "CREATE TABLE IF NOT EXISTS 'messages' ( \
-                    'update_id' INTEGER UNIQUE, \
-                    'kind' TEXT NOT NULL, \
-                    'chat_id' INTEGER, \
-                    'message_id' INTEGER, \
-                    'from_id' TEXT, \
-                    'content' TEXT, \
-                    'raw' TEXT, \
-                    PRIMARY KEY('update_id') \
-                );")
-            .execute(pool)
-            .await
+    fn parse_message(&mut self, msg: &TgMessage) {
+        self.chat_id = Some(msg.chat.id);
+        self.message_id = Some(msg.id);
+        self.from_id = msg.from().map(|user| user.id);
+        self.content = msg.text().map(|text| text.to_string());
+        self.caption = msg.caption().map(|caption| caption.to_string());
+        if let Some((media_type, file)) = get_media(msg) {
+            self.media_type = Some(media_type.to_string());
+            self.file_id = file.map(|file| file.id.clone());
+        }
+        self.sticker_emoji = msg.sticker().and_then(|sticker| sticker.emoji.clone());
+        if let Some(poll) = msg.poll() {
+            self.parse_poll(poll);
+        }
+        self.forwarded_from = msg.forward_from().map(forwarded_from_to_string);
+        self.reply_to_message_id = msg.reply_to_message().map(|reply| reply.id.0);
+        self.entities = msg.entities().or(msg.caption_entities()).and_then(|entities| serde_json::to_string(entities).ok());
+    }
+
+    fn parse_poll(&mut self, poll: &Poll) {
+        let options: Vec<&str> = poll.options.iter().map(|option| option.text.as_str()).collect();
+        self.poll_question = Some(poll.question.clone());
+        self.poll_options = serde_json::to_string(&options).ok();
     }
 
     pub async fn insert(&self, pool: &SqlitePool) -> Result<SqliteQueryResult, sqlx::Error> {
         sqlx::query(
             "INSERT OR IGNORE INTO messages \
-            (update_id, kind, chat_id, message_id, from_id, content, raw) \
-            VALUES (?, ?, ?, ?, ?, ?, ?)")
-            .bind(&self.update_id)
+            (update_id, kind, chat_id, message_id, from_id, content, caption, media_type, file_id, sticker_emoji, \
+            poll_question, poll_options, forwarded_from, reply_to_message_id, entities, raw) \
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)")
+            .bind(self.update_id)
             .bind(&self.kind)
-            .bind(&self.chat_id.map_or(None, |chat_id| Some(chat_id.0.to_string())))
-            .bind(&self.message_id.map_or(None, |message_id| Some(message_id.0)))
-            .bind(&self.from_id.map_or(None, |user_id| Some(user_id.0.to_string())))
+            .bind(self.chat_id.map(|chat_id| chat_id.0.to_string()))
+            .bind(self.message_id.map(|message_id| message_id.0))
+            .bind(self.from_id.map(|user_id| user_id.0.to_string()))
             .bind(&self.content)
+            .bind(&self.caption)
+            .bind(&self.media_type)
+            .bind(&self.file_id)
+            .bind(&self.sticker_emoji)
+            .bind(&self.poll_question)
+            .bind(&self.poll_options)
+            .bind(&self.forwarded_from)
+            .bind(self.reply_to_message_id)
+            .bind(&self.entities)
             .bind(&self.raw)
             .execute(pool)
             .await
     }
 }
 
-fn parse_message(msg: &TgMessage) -> (Option<ChatId>, Option<MessageId>, Option<UserId>, Option<String>) {
-    let chat_id = Some(msg.chat.id);
-    let message_id = Some(msg.id);
-    let from_id = msg.from().map_or(None, |user| Some(user.id));
-    let content = msg.text().map_or(None, |text| Some(text.to_string()));
-
-    (chat_id, message_id, from_id, content)
+/// Kind of the attached media and its file, the largest size of a photo.
+fn get_media(msg: &TgMessage) -> Option<(&'static str, Option<&FileMeta>)> {
+    msg.photo().map(|sizes| ("photo", sizes.iter().max_by_key(|size| size.width * size.height).map(|size| &size.file)))
+        .or_else(|| msg.animation().map(|animation| ("animation", Some(&animation.file))))
+        .or_else(|| msg.video().map(|video| ("video", Some(&video.file))))
+        .or_else(|| msg.video_note().map(|video_note| ("video_note", Some(&video_note.file))))
+        .or_else(|| msg.voice().map(|voice| ("voice", Some(&voice.file))))
+        .or_else(|| msg.audio().map(|audio| ("audio", Some(&audio.file))))
+        .or_else(|| msg.document().map(|document| ("document", Some(&document.file))))
+        .or_else(|| msg.sticker().map(|sticker| ("sticker", Some(&sticker.file))))
+        .or_else(|| msg.poll().map(|_| ("poll", None)))
+        .or_else(|| msg.venue().map(|_| ("venue", None)))
+        .or_else(|| msg.location().map(|_| ("location", None)))
+        .or_else(|| msg.contact().map(|_| ("contact", None)))
+        .or_else(|| msg.dice().map(|_| ("dice", None)))
 }
 
-fn parse_unknown<T>(_: T) -> (Option<ChatId>, Option<MessageId>, Option<UserId>, Option<String>) {
-    (None, None, None, None)
+fn forwarded_from_to_string(from: &ForwardedFrom) -> String {
+    match from {
+        ForwardedFrom::User(user) => format!("user:{}", user.id),
+        ForwardedFrom::Chat(chat) => format!("chat:{}", chat.id),
+        ForwardedFrom::SenderName(name) => format!("name:{}", name),
+    }
 }
 
 fn upd_kind_to_string(kind: &UpdateKind) -> &'static str {
@@ -118,3 +179,71 @@ fn get_raw(u: &Update) -> Option<String> {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use crate::fixtures::parse_update;
+    use super::*;
+
+    #[test]
+    fn test_photo_message() {
+        let message = Message::from(&parse_update(include_str!("../../fixtures/updates/photo_message.json")));
+
+        assert_eq!(message.content, None);
+        assert_eq!(message.caption.as_deref(), Some("Look at #lunch"));
+        assert_eq!(message.media_type.as_deref(), Some("photo"));
+        assert_eq!(message.file_id.as_deref(), Some("photo-large"));
+        assert_eq!(message.forwarded_from.as_deref(), Some("user:1003"));
+        assert_eq!(message.reply_to_message_id, Some(501));
+        assert!(message.entities.unwrap().contains("hashtag"));
+    }
+
+    #[test]
+    fn test_sticker_message() {
+        let message = Message::from(&parse_update(include_str!("../../fixtures/updates/sticker_message.json")));
+
+        assert_eq!(message.media_type.as_deref(), Some("sticker"));
+        assert_eq!(message.file_id.as_deref(), Some("sticker-file"));
+        assert_eq!(message.sticker_emoji.as_deref(), Some("🍕"));
+        assert_eq!(message.forwarded_from, None);
+    }
+
+    #[test]
+    fn test_poll() {
+        let message = Message::from(&parse_update(include_str!("../../fixtures/updates/poll.json")));
+
+        assert_eq!(message.poll_question.as_deref(), Some("Lunch?"));
+        assert_eq!(message.poll_options.as_deref(), Some(r#"["Pizza","Sushi"]"#));
+    }
+
+    #[test]
+    fn test_ids_of_other_kinds() {
+        let callback = Message::from(&parse_update(include_str!("../../fixtures/updates/callback_query.json")));
+        assert_eq!(callback.from_id, Some(UserId(1002)));
+        assert_eq!(callback.content.as_deref(), Some("button_1"));
+
+        let join_request = Message::from(&parse_update(include_str!("../../fixtures/updates/chat_join_request.json")));
+        assert_eq!(join_request.chat_id, Some(ChatId(-100200)));
+        assert_eq!(join_request.from_id, Some(UserId(1004)));
+
+        let poll_answer = Message::from(&parse_update(include_str!("../../fixtures/updates/poll_answer.json")));
+        assert_eq!(poll_answer.from_id, Some(UserId(1002)));
+        assert_eq!(poll_answer.content.as_deref(), Some("[1]"));
+    }
+
+    #[tokio::test]
+    async fn test_insert() {
+        let db = crate::db::Db::in_memory().await;
+
+        let update = parse_update(include_str!("../../fixtures/updates/photo_message.json"));
+        db.save_update(&update).await.unwrap();
+
+        let (caption, reply_to): (Option<String>, Option<i32>) = sqlx::query_as("SELECT caption, reply_to_message_id FROM messages WHERE update_id = ?")
+            .bind(update.id)
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(caption.as_deref(), Some("Look at #lunch"));
+        assert_eq!(reply_to, Some(501));
+    }
+}
@@ -0,0 +1,150 @@
+use std::fmt::{Display, Formatter};
+use log::info;
+use sqlx::{Executor, Row, SqlitePool};
+use crate::error::{Error, Result};
+
+/// A schema change embedded into the binary. Released migrations are never edited, a change is a new one.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    sql: &'static str,
+}
+
+/// Applied in order, each one exactly once.
+const MIGRATIONS: &[Migration] = &[
+    Migration { version: 1, name: "initial", sql: include_str!("migrations/0001_initial.sql") },
+    Migration { version: 2, name: "dead_letters", sql: include_str!("migrations/0002_dead_letters.sql") },
+    Migration { version: 3, name: "chats", sql: include_str!("migrations/0003_chats.sql") },
+    Migration { version: 4, name: "inbox", sql: include_str!("migrations/0004_inbox.sql") },
+    Migration { version: 5, name: "message_content", sql: include_str!("migrations/0005_message_content.sql") },
+];
+
+impl Display for Migration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:04}_{}", self.version, self.name)
+    }
+}
+
+async fn create_version_table(pool: &SqlitePool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+            "CREATE TABLE IF NOT EXISTS 'schema_version' ( \
+                'version' INTEGER NOT NULL, \
+                'name' TEXT NOT NULL, \
+                'applied_at' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP, \
+                PRIMARY KEY('version') \
+            );")
+        .execute(pool)
+        .await?;
+
+    Ok(())
+}
+
+/// Version of the last applied migration, 0 for a database which has none. Doesn't write to the database.
+pub async fn current_version(pool: &SqlitePool) -> Result<i64, sqlx::Error> {
+    let versioned = sqlx::query("SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'schema_version'")
+        .fetch_optional(pool)
+        .await?
+        .is_some();
+    if !versioned {
+        return Ok(0);
+    }
+
+    sqlx::query("SELECT COALESCE(MAX(version), 0) FROM schema_version")
+        .fetch_one(pool)
+        .await?
+        .try_get(0)
+}
+
+/// Migrations newer than the database. A database newer than the binary is refused, there are no downgrades.
+pub async fn pending(pool: &SqlitePool) -> Result<Vec<&'static Migration>> {
+    let version = current_version(pool).await?;
+    let latest = MIGRATIONS.last().map_or(0, |migration| migration.version);
+    if version > latest {
+        return Err(Error::Schema(format!("database schema version {} is newer than the latest known version {}", version, latest)));
+    }
+
+    Ok(MIGRATIONS.iter().filter(|migration| migration.version > version).collect())
+}
+
+/// Applies the pending migrations, each in its own transaction together with its version.
+pub async fn migrate(pool: &SqlitePool) -> Result<()> {
+    create_version_table(pool).await?;
+    for migration in pending(pool).await? {
+        let mut tx = pool.begin().await?;
+        tx.execute(migration.sql).await?;
+        sqlx::query("INSERT INTO schema_version (version, name) VALUES (?, ?)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+        info!("Applied migration {}", migration);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::db::Db;
+    use super::*;
+
+    /// Unlike `Db::in_memory`, nothing is migrated yet.
+    async fn empty_db() -> Db {
+        Db::new(":memory:".to_string()).await.unwrap()
+    }
+
+    #[test]
+    fn test_versions_are_sequential() {
+        for (i, migration) in MIGRATIONS.iter().enumerate() {
+            assert_eq!(migration.version, i as i64 + 1, "{}", migration);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_migrate_applies_each_migration_once() {
+        let db = empty_db().await;
+        assert_eq!(pending(&db.pool).await.unwrap().len(), MIGRATIONS.len());
+
+        migrate(&db.pool).await.unwrap();
+        migrate(&db.pool).await.unwrap();
+
+        assert_eq!(current_version(&db.pool).await.unwrap(), MIGRATIONS.len() as i64);
+        assert!(pending(&db.pool).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unversioned_database_is_upgraded() {
+        let db = empty_db().await;
+        db.pool.execute(
+            "CREATE TABLE conf (key TEXT PRIMARY KEY, value TEXT); \
+            INSERT INTO conf (key, value) VALUES ('CHAT_ID', '-100200'), ('GPT_MODEL', 'gpt-4'); \
+            CREATE TABLE 'permissions' ('user_id' INTEGER NOT NULL, 'is_bot_admin' BOOLEAN NOT NULL DEFAULT 0, 'custom_tag' TEXT, PRIMARY KEY('user_id'));"
+        ).await.unwrap();
+
+        migrate(&db.pool).await.unwrap();
+
+        assert_eq!(db.list_chats().await.unwrap(), vec![teloxide::types::ChatId(-100200)]);
+        assert_eq!(db.read_conf_value::<String>(crate::db::ConfKey::GptModel).await.unwrap(), Some("gpt-4".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_version_is_read_without_writing() {
+        let db = empty_db().await;
+
+        assert_eq!(current_version(&db.pool).await.unwrap(), 0);
+        assert_eq!(pending(&db.pool).await.unwrap().len(), MIGRATIONS.len());
+
+        let tables = sqlx::query("SELECT name FROM sqlite_master").fetch_all(&db.pool).await.unwrap();
+        assert!(tables.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_newer_database_is_refused() {
+        let db = empty_db().await;
+        migrate(&db.pool).await.unwrap();
+        sqlx::query("INSERT INTO schema_version (version, name) VALUES (1000, 'future')").execute(&db.pool).await.unwrap();
+
+        assert!(matches!(pending(&db.pool).await, Err(Error::Schema(_))));
+    }
+}
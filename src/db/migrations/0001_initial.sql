@@ -0,0 +1,62 @@
+-- the tables created before schema versioning, IF NOT EXISTS lets databases of that time upgrade
+CREATE TABLE IF NOT EXISTS conf (key TEXT PRIMARY KEY, value TEXT);
+
+INSERT OR IGNORE INTO conf (key, value) VALUES
+    ('OFFSET', NULL),
+    ('GPT_PROMPT', NULL),
+    ('HISTORY_CAPACITY', '15'),
+    ('PAUSED', 'false'),
+    ('REPLY_MODE', 'last'),
+    ('TRIGGERS', 'every'),
+    ('TRIGGER_KEYWORDS', NULL),
+    ('TRIGGER_CHANCE', NULL),
+    ('PRESERVE_CONTEXT', 'false'),
+    ('SUMMARIZE', 'false'),
+    ('STREAMING', 'false'),
+    ('FALLBACK_MESSAGE', NULL),
+    ('GPT_MODEL', 'gpt-3.5-turbo-0301'),
+    ('GPT_TEMPERATURE', NULL),
+    ('GPT_TOP_P', NULL),
+    ('GPT_MAX_TOKENS', NULL),
+    ('GPT_PRESENCE_PENALTY', NULL),
+    ('GPT_FREQUENCY_PENALTY', NULL),
+    ('GPT_STOP', NULL),
+    ('GPT_CONTEXT_SIZE', NULL);
+
+CREATE TABLE IF NOT EXISTS 'users' (
+    'chat_id' INTEGER NOT NULL,
+    'id' INTEGER NOT NULL,
+    'username' TEXT,
+    'first_name' TEXT NOT NULL,
+    'last_name' TEXT,
+    'first_seen' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    'last_seen' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    'message_count' INTEGER NOT NULL DEFAULT 0,
+    PRIMARY KEY('chat_id', 'id')
+);
+
+CREATE TABLE IF NOT EXISTS 'user_name_history' (
+    'chat_id' INTEGER NOT NULL,
+    'user_id' INTEGER NOT NULL,
+    'old_name' TEXT,
+    'new_name' TEXT,
+    'changed_at' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE TABLE IF NOT EXISTS 'permissions' (
+    'user_id' INTEGER NOT NULL,
+    'is_bot_admin' BOOLEAN NOT NULL DEFAULT 0,
+    'custom_tag' TEXT,
+    PRIMARY KEY('user_id')
+);
+
+CREATE TABLE IF NOT EXISTS 'messages' (
+    'update_id' INTEGER UNIQUE,
+    'kind' TEXT NOT NULL,
+    'chat_id' INTEGER,
+    'message_id' INTEGER,
+    'from_id' TEXT,
+    'content' TEXT,
+    'raw' TEXT,
+    PRIMARY KEY('update_id')
+);
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS 'dead_letters' (
+    'id' INTEGER PRIMARY KEY AUTOINCREMENT,
+    'update_id' INTEGER,
+    'stage' TEXT NOT NULL,
+    'error' TEXT NOT NULL,
+    'raw' TEXT,
+    'created_at' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
@@ -0,0 +1,21 @@
+-- a NULL setting falls back to the value in the conf table
+CREATE TABLE IF NOT EXISTS 'chats' (
+    'chat_id' INTEGER NOT NULL,
+    'prompt' TEXT,
+    'model' TEXT,
+    'history_capacity' TEXT,
+    'triggers' TEXT,
+    'trigger_keywords' TEXT,
+    'trigger_chance' TEXT,
+    'paused' TEXT,
+    'summary' TEXT,
+    'added_at' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY('chat_id')
+);
+
+-- the chat id and the summary of the single chat setup move from the conf table
+INSERT OR IGNORE INTO chats (chat_id, summary)
+SELECT CAST(value AS INTEGER), (SELECT value FROM conf WHERE key = 'HISTORY_SUMMARY')
+FROM conf WHERE key = 'CHAT_ID' AND value IS NOT NULL;
+
+DELETE FROM conf WHERE key IN ('CHAT_ID', 'HISTORY_SUMMARY');
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS 'inbox' (
+    'update_id' INTEGER NOT NULL,
+    'chat_id' INTEGER,
+    'state' TEXT NOT NULL,
+    'raw' TEXT NOT NULL,
+    'error' TEXT,
+    'received_at' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    'updated_at' DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY('update_id')
+);
@@ -0,0 +1,12 @@
+ALTER TABLE messages ADD COLUMN 'caption' TEXT;
+ALTER TABLE messages ADD COLUMN 'media_type' TEXT;
+ALTER TABLE messages ADD COLUMN 'file_id' TEXT;
+ALTER TABLE messages ADD COLUMN 'sticker_emoji' TEXT;
+ALTER TABLE messages ADD COLUMN 'poll_question' TEXT;
+-- JSON array of the option texts
+ALTER TABLE messages ADD COLUMN 'poll_options' TEXT;
+-- 'user:<id>', 'chat:<id>' or 'name:<sender name>'
+ALTER TABLE messages ADD COLUMN 'forwarded_from' TEXT;
+ALTER TABLE messages ADD COLUMN 'reply_to_message_id' INTEGER;
+-- JSON array of the text or caption entities
+ALTER TABLE messages ADD COLUMN 'entities' TEXT;
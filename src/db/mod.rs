@@ -1,68 +1,210 @@
-use std::error::Error;
+use std::fmt::Display;
 use std::str::FromStr;
+use std::time::Duration;
 use sqlx::{Pool, Row, sqlite::Sqlite, SqlitePool};
 use teloxide::prelude::*;
+use teloxide::types::User;
+use crate::chat_data::{ChannelUserUpdateResult, ChatMember};
+use crate::error::{Error, Result};
 
+mod chats;
+mod dead_letters;
+mod inbox;
+mod migrations;
 mod messages;
 mod permissions;
+mod users;
 
+pub use inbox::InboxState;
+pub use migrations::Migration;
+pub use permissions::Permissions;
+
+#[derive(Clone)]
 pub struct Db {
     pool: Pool<Sqlite>,
 }
 
 pub enum ConfKey {
     Offset,
-    ChatId,
-    GptPrompt
+    GptPrompt,
+    HistoryCapacity,
+    Paused,
+    ReplyMode,
+    Triggers,
+    TriggerKeywords,
+    TriggerChance,
+    PreserveContext,
+    Summarize,
+    Streaming,
+    FallbackMessage,
+    GptModel,
+    GptTemperature,
+    GptTopP,
+    GptMaxTokens,
+    GptPresencePenalty,
+    GptFrequencyPenalty,
+    GptStop,
+    GptContextSize,
 }
 
 impl ConfKey {
     fn get_db_key(&self) -> &'static str {
         match self {
             ConfKey::Offset => "OFFSET",
-            ConfKey::ChatId => "CHAT_ID",
             ConfKey::GptPrompt => "GPT_PROMPT",
+            ConfKey::HistoryCapacity => "HISTORY_CAPACITY",
+            ConfKey::Paused => "PAUSED",
+            ConfKey::ReplyMode => "REPLY_MODE",
+            ConfKey::Triggers => "TRIGGERS",
+            ConfKey::TriggerKeywords => "TRIGGER_KEYWORDS",
+            ConfKey::TriggerChance => "TRIGGER_CHANCE",
+            ConfKey::PreserveContext => "PRESERVE_CONTEXT",
+            ConfKey::Summarize => "SUMMARIZE",
+            ConfKey::Streaming => "STREAMING",
+            ConfKey::FallbackMessage => "FALLBACK_MESSAGE",
+            ConfKey::GptModel => "GPT_MODEL",
+            ConfKey::GptTemperature => "GPT_TEMPERATURE",
+            ConfKey::GptTopP => "GPT_TOP_P",
+            ConfKey::GptMaxTokens => "GPT_MAX_TOKENS",
+            ConfKey::GptPresencePenalty => "GPT_PRESENCE_PENALTY",
+            ConfKey::GptFrequencyPenalty => "GPT_FREQUENCY_PENALTY",
+            ConfKey::GptStop => "GPT_STOP",
+            ConfKey::GptContextSize => "GPT_CONTEXT_SIZE",
+        }
+    }
+}
+
+/// Settings a chat can have its own value of, the others apply to all chats.
+#[derive(Debug, Clone, Copy)]
+pub enum ChatKey {
+    Prompt,
+    Model,
+    HistoryCapacity,
+    Triggers,
+    TriggerKeywords,
+    TriggerChance,
+    Paused,
+    Summary,
+}
+
+impl ChatKey {
+    fn get_db_column(&self) -> &'static str {
+        match self {
+            ChatKey::Prompt => "prompt",
+            ChatKey::Model => "model",
+            ChatKey::HistoryCapacity => "history_capacity",
+            ChatKey::Triggers => "triggers",
+            ChatKey::TriggerKeywords => "trigger_keywords",
+            ChatKey::TriggerChance => "trigger_chance",
+            ChatKey::Paused => "paused",
+            ChatKey::Summary => "summary",
+        }
+    }
+
+    /// The conf value used while the chat has none of its own.
+    fn default_key(&self) -> Option<ConfKey> {
+        match self {
+            ChatKey::Prompt => Some(ConfKey::GptPrompt),
+            ChatKey::Model => Some(ConfKey::GptModel),
+            ChatKey::HistoryCapacity => Some(ConfKey::HistoryCapacity),
+            ChatKey::Triggers => Some(ConfKey::Triggers),
+            ChatKey::TriggerKeywords => Some(ConfKey::TriggerKeywords),
+            ChatKey::TriggerChance => Some(ConfKey::TriggerChance),
+            ChatKey::Paused => Some(ConfKey::Paused),
+            ChatKey::Summary => None,
         }
     }
 }
 
 impl Db {
-    pub async fn new(url: String) -> Result<Self, Box<dyn Error>> {
+    pub async fn new(url: String) -> Result<Self> {
         let url = format!("sqlite://{}", url);
         let pool = SqlitePool::connect(&url).await?;
 
         Ok(Self { pool })
     }
 
-    pub async fn migrate(&self) -> Result<(), Box<dyn Error>> {
-        sqlx::query("CREATE TABLE IF NOT EXISTS conf (key TEXT PRIMARY KEY, value TEXT)").execute(&self.pool).await?;
-        sqlx::query(
-            "INSERT OR IGNORE INTO conf (key, value) VALUES \
-            ('OFFSET', NULL), \
-            ('CHAT_ID', NULL), \
-            ('GPT_PROMPT', NULL) \
-            ").execute(&self.pool).await?;
+    /// A migrated database which lives as long as the pool.
+    #[cfg(test)]
+    pub async fn in_memory() -> Self {
+        let db = Self::new(":memory:".to_string()).await.unwrap();
+        db.migrate().await.unwrap();
+        db
+    }
+
+    pub async fn close(&self) {
+        self.pool.close().await;
+    }
 
-        //sqlx::query("CREATE TABLE IF NOT EXISTS users (id INTEGER PRIMARY KEY, name TEXT)").execute(&self.pool).await?;
+    /// Brings the schema up to date, see `migrations`.
+    pub async fn migrate(&self) -> Result<()> {
+        migrations::migrate(&self.pool).await
+    }
 
-        messages::Message::create_table(&self.pool).await?;
+    pub async fn schema_version(&self) -> Result<i64, sqlx::Error> {
+        migrations::current_version(&self.pool).await
+    }
 
-        Ok(())
+    pub async fn pending_migrations(&self) -> Result<Vec<&'static Migration>> {
+        migrations::pending(&self.pool).await
     }
 
-    pub async fn read_conf_value<T>(&self, key: ConfKey) -> Result<Option<T>, Box<dyn Error>>
+    pub async fn read_conf_value<T>(&self, key: ConfKey) -> Result<Option<T>>
         where T: FromStr,
-              <T as FromStr>::Err: Error {
+              <T as FromStr>::Err: Display {
         let key = key.get_db_key();
         let Some(string_value) = self.read_conf_value_raw(key).await? else { return Ok(None); };
 
         match string_value.parse::<T>() {
             Ok(value) => Ok(Some(value)),
-            Err(_) => Err(format!("Error parsing value by key '{}', value: '{}'", key, string_value).into())
+            Err(e) => Err(Error::Config(format!("Error parsing value by key '{}', value: '{}': {}", key, string_value, e)))
+        }
+    }
+
+    /// Reads the setting of the chat, or the conf value if the chat has none.
+    pub async fn read_chat_value<T>(&self, chat_id: ChatId, key: ChatKey) -> Result<Option<T>>
+        where T: FromStr,
+              <T as FromStr>::Err: Display {
+        let column = key.get_db_column();
+        let Some(string_value) = chats::Chat::select_value(&self.pool, chat_id, column).await? else {
+            return match key.default_key() {
+                Some(key) => self.read_conf_value(key).await,
+                None => Ok(None),
+            };
+        };
+
+        match string_value.parse::<T>() {
+            Ok(value) => Ok(Some(value)),
+            Err(e) => Err(Error::Config(format!("Error parsing {} of chat {}, value: '{}': {}", column, chat_id, string_value, e)))
         }
     }
 
-    async fn read_conf_value_raw(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+    /// Sets the setting of the chat only, `None` makes it fall back to the conf value again.
+    /// Fails for a chat which is not in the chats table, the setting would be lost otherwise.
+    pub async fn write_chat_value(&self, chat_id: ChatId, key: ChatKey, value: Option<String>) -> Result<()> {
+        let result = chats::Chat::update_value(&self.pool, chat_id, key.get_db_column(), value).await?;
+        if result.rows_affected() == 0 {
+            return Err(Error::Config(format!("Chat {} is not set, add it with -c {}", chat_id, chat_id)));
+        }
+
+        Ok(())
+    }
+
+    pub async fn add_chat(&self, chat_id: ChatId) -> Result<(), sqlx::Error> {
+        chats::Chat::insert(&self.pool, chat_id).await?;
+        Ok(())
+    }
+
+    pub async fn remove_chat(&self, chat_id: ChatId) -> Result<(), sqlx::Error> {
+        chats::Chat::delete(&self.pool, chat_id).await?;
+        Ok(())
+    }
+
+    pub async fn list_chats(&self) -> Result<Vec<ChatId>, sqlx::Error> {
+        chats::Chat::select_ids(&self.pool).await
+    }
+
+    async fn read_conf_value_raw(&self, key: &str) -> Result<Option<String>> {
         let conf = sqlx::query("SELECT value FROM conf WHERE key = ?").bind(key).fetch_optional(&self.pool).await?;
 
         let Some(row) = conf else { return Ok(None); };
@@ -86,17 +228,125 @@ impl Db {
         Ok(())
     }
 
-    pub(crate) async fn set_bot_admin(&self, _user_id: u64) -> Result<(), Box<dyn Error>> {
-        // TBD
+    pub async fn set_bot_admin(&self, user_id: UserId, is_bot_admin: bool) -> Result<(), sqlx::Error> {
+        Permissions::set_bot_admin(&self.pool, user_id, is_bot_admin).await?;
         Ok(())
     }
 
-    pub async fn save_updates(&self, updates: &Vec<Update>) -> Result<(), Box<dyn Error>> {
+    pub async fn is_bot_admin(&self, user_id: UserId) -> Result<bool, sqlx::Error> {
+        let permissions = Permissions::select(&self.pool, user_id).await?;
+        Ok(permissions.map_or(false, |p| p.is_bot_admin()))
+    }
+
+    pub async fn list_permissions(&self) -> Result<Vec<Permissions>, sqlx::Error> {
+        Permissions::select_all(&self.pool).await
+    }
+
+    pub async fn save_user(&self, chat_id: ChatId, user: &User, is_message: bool, result: &ChannelUserUpdateResult) -> Result<(), sqlx::Error> {
+        let user = users::User::from(chat_id, user);
+        user.upsert(&self.pool, is_message as i64).await?;
+
+        if let ChannelUserUpdateResult::Update(old) = result {
+            user.insert_name_change(&self.pool, old).await?;
+        }
+
+        Ok(())
+    }
+
+    pub async fn load_chat_members(&self, chat_id: ChatId) -> Result<Vec<ChatMember>, sqlx::Error> {
+        let users = users::User::select_by_chat(&self.pool, chat_id).await?;
+        Ok(users.into_iter().map(ChatMember::from).collect())
+    }
+
+    pub async fn save_update(&self, update: &Update) -> Result<(), sqlx::Error> {
+        messages::Message::from(update).insert(&self.pool).await?;
+        Ok(())
+    }
+
+    /// Also marks the update as failed in the inbox.
+    pub async fn save_dead_letter(&self, update: Option<&Update>, stage: &str, error: &str) -> Result<(), sqlx::Error> {
+        dead_letters::DeadLetter::new(update, stage, error).insert(&self.pool).await?;
+        if let Some(update) = update {
+            self.set_update_state(update.id, InboxState::Failed, Some(error)).await?;
+        }
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub async fn dead_letters(&self) -> Result<Vec<(Option<i32>, String, String)>, sqlx::Error> {
+        dead_letters::DeadLetter::select_all(&self.pool).await
+    }
+
+    /// Commits the updates to the inbox together with the offset following them.
+    /// Either both are saved or neither, so an update is never skipped nor received twice.
+    /// Returns the ids of the updates which weren't in the inbox already.
+    pub async fn receive_updates(&self, updates: &[&Update], offset: Option<i32>) -> Result<Vec<i32>> {
+        let mut tx = self.pool.begin().await?;
+        let mut new_ids = Vec::with_capacity(updates.len());
         for update in updates {
-            let msg = messages::Message::from(update);
-            msg.insert(&self.pool).await?;
+            if inbox::Inbox::from(update)?.insert(&mut tx).await? {
+                new_ids.push(update.id);
+            }
         }
+        sqlx::query("UPDATE conf SET value = ? WHERE key = ?")
+            .bind(offset)
+            .bind(ConfKey::Offset.get_db_key())
+            .execute(&mut tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(new_ids)
+    }
+
+    /// Commits an update pushed to the webhook, which has no offset.
+    /// Returns `false` if the update is in the inbox already.
+    pub async fn receive_update(&self, update: &Update) -> Result<bool> {
+        Ok(inbox::Inbox::from(update)?.insert(&self.pool).await?)
+    }
+
+    /// Updates committed to the inbox but not handled yet, oldest first.
+    pub async fn received_updates(&self) -> Result<Vec<Update>, sqlx::Error> {
+        inbox::Inbox::select_received(&self.pool).await
+    }
+
+    /// Marks the updates as being handled before anything is sent for them, so a restart doesn't handle them again.
+    /// Returns the ids of the updates claimed, those claimed or handled before are left out.
+    pub async fn claim_updates(&self, update_ids: &[i32]) -> Result<Vec<i32>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let mut claimed = Vec::with_capacity(update_ids.len());
+        for update_id in update_ids {
+            if inbox::Inbox::claim(&mut tx, *update_id).await? {
+                claimed.push(*update_id);
+            }
+        }
+        tx.commit().await?;
+
+        Ok(claimed)
+    }
+
+    /// Fails the updates whose handling was interrupted, it is unknown whether they have been responded to.
+    /// Returns the number of such updates.
+    pub async fn fail_interrupted_updates(&self) -> Result<u64, sqlx::Error> {
+        let result = inbox::Inbox::update_all_states(&self.pool, InboxState::Processing, InboxState::Failed, Some("interrupted while processing")).await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Deletes the updates processed longer than `retention` ago, returns how many.
+    pub async fn prune_inbox(&self, retention: Duration) -> Result<u64, sqlx::Error> {
+        let result = inbox::Inbox::delete_processed(&self.pool, retention).await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Marks the updates handled by a chat worker, except those that have failed meanwhile.
+    pub async fn mark_processed(&self, update_ids: &[i32]) -> Result<(), sqlx::Error> {
+        for update_id in update_ids {
+            self.set_update_state(*update_id, InboxState::Processed, None).await?;
+        }
+        Ok(())
+    }
 
+    pub async fn set_update_state(&self, update_id: i32, state: InboxState, error: Option<&str>) -> Result<(), sqlx::Error> {
+        inbox::Inbox::update_state(&self.pool, update_id, state, error).await?;
         Ok(())
     }
 }
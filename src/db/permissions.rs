@@ -1,31 +1,51 @@
-use sqlx::{FromRow};
+use std::fmt::{Display, Formatter};
+use sqlx::{FromRow, SqlitePool};
+use sqlx::sqlite::SqliteQueryResult;
 use teloxide::prelude::*;
 
 #[derive(Debug, FromRow)]
 pub struct Permissions {
-    user_id: UserId,
+    user_id: i64,
     is_bot_admin: bool,
     custom_tag: Option<String>,
 }
-/*
+
 impl Permissions {
-    pub async fn set_bot_admin(&mut self, _pool: &sqlx::SqlitePool) -> Result<(), sqlx::Error> {
-        self.is_bot_admin = true;
+    pub fn is_bot_admin(&self) -> bool {
+        self.is_bot_admin
+    }
 
-        // sqlx::query("INSERT OR IGNORE permissions (user_id, is_bot_admin) VALUES (?, 1)")
-        //     .bind(&self.user_id.0.to_string())
-        //     .bind(&self.is_bot_admin)
-        //     .execute(pool)
-        //     .await?;
+    pub async fn set_bot_admin(pool: &SqlitePool, user_id: UserId, is_bot_admin: bool) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO permissions (user_id, is_bot_admin) VALUES (?, ?) \
+            ON CONFLICT (user_id) DO UPDATE SET is_bot_admin = excluded.is_bot_admin")
+            .bind(user_id.0 as i64)
+            .bind(is_bot_admin)
+            .execute(pool)
+            .await
+    }
 
-        // sqlx::query("UPDATE permissions SET is_bot_admin = 1 WHERE user_id = ?")
-        //     .bind(self.user_id)
-        //     .execute(pool)
-        //     .await?;
+    pub async fn select(pool: &SqlitePool, user_id: UserId) -> Result<Option<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Self>("SELECT user_id, is_bot_admin, custom_tag FROM permissions WHERE user_id = ?")
+            .bind(user_id.0 as i64)
+            .fetch_optional(pool)
+            .await
+    }
+
+    pub async fn select_all(pool: &SqlitePool) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Self>("SELECT user_id, is_bot_admin, custom_tag FROM permissions ORDER BY user_id")
+            .fetch_all(pool)
+            .await
+    }
+}
 
-        //Ok(())
+impl Display for Permissions {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "user {}: bot admin: {}", self.user_id, self.is_bot_admin)?;
+        if let Some(tag) = &self.custom_tag {
+            write!(f, ", tag: '{}'", tag)?;
+        }
 
-        todo!()
+        Ok(())
     }
 }
- */
\ No newline at end of file
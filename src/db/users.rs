@@ -0,0 +1,85 @@
+use sqlx::{FromRow, SqlitePool};
+use sqlx::sqlite::SqliteQueryResult;
+use teloxide::prelude::{ChatId, UserId};
+use teloxide::types::User as TgUser;
+use crate::chat_data::ChatMember;
+
+#[derive(Debug, FromRow)]
+pub struct User {
+    chat_id: i64,
+    id: i64,
+    username: Option<String>,
+    first_name: String,
+    last_name: Option<String>,
+}
+
+impl User {
+    pub fn from(chat_id: ChatId, user: &TgUser) -> Self {
+        Self {
+            chat_id: chat_id.0,
+            id: user.id.0 as i64,
+            username: user.username.clone(),
+            first_name: user.first_name.clone(),
+            last_name: user.last_name.clone(),
+        }
+    }
+
+    pub async fn upsert(&self, pool: &SqlitePool, messages: i64) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO users \
+            (chat_id, id, username, first_name, last_name, message_count) \
+            VALUES (?, ?, ?, ?, ?, ?) \
+            ON CONFLICT (chat_id, id) DO UPDATE SET \
+            username = excluded.username, \
+            first_name = excluded.first_name, \
+            last_name = excluded.last_name, \
+            last_seen = CURRENT_TIMESTAMP, \
+            message_count = message_count + excluded.message_count")
+            .bind(self.chat_id)
+            .bind(self.id)
+            .bind(&self.username)
+            .bind(&self.first_name)
+            .bind(&self.last_name)
+            .bind(messages)
+            .execute(pool)
+            .await
+    }
+
+    pub async fn insert_name_change(&self, pool: &SqlitePool, old: &ChatMember) -> Result<SqliteQueryResult, sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO user_name_history \
+            (chat_id, user_id, old_name, new_name) \
+            VALUES (?, ?, ?, ?)")
+            .bind(self.chat_id)
+            .bind(self.id)
+            .bind(&old.name)
+            .bind(self.full_name())
+            .execute(pool)
+            .await
+    }
+
+    pub async fn select_by_chat(pool: &SqlitePool, chat_id: ChatId) -> Result<Vec<Self>, sqlx::Error> {
+        sqlx::query_as::<_, Self>(
+            "SELECT chat_id, id, username, first_name, last_name FROM users WHERE chat_id = ?")
+            .bind(chat_id.0)
+            .fetch_all(pool)
+            .await
+    }
+
+    // same as teloxide::types::User::full_name, so that reloaded members compare equal to fresh ones
+    fn full_name(&self) -> String {
+        match &self.last_name {
+            Some(last_name) => format!("{} {}", self.first_name, last_name),
+            None => self.first_name.clone(),
+        }
+    }
+}
+
+impl From<User> for ChatMember {
+    fn from(user: User) -> Self {
+        Self {
+            id: UserId(user.id as u64),
+            name: Some(user.full_name()),
+        }
+    }
+}
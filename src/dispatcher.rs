@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::future::Future;
+use log::{error, info, warn};
+use teloxide::dispatching::dialogue::GetChatId;
+use teloxide::types::{ChatId, Update};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio::task::JoinHandle;
+use crate::backend::CompletionBackend;
+use crate::chat_data::ChatData;
+use crate::db::Db;
+use crate::error::{Error, Result};
+use crate::gpt::Gpt;
+
+/// Updates waiting for the worker of a chat, the poller stops when a queue is full.
+const CHAT_QUEUE_SIZE: usize = 64;
+
+/// Everything the bot keeps about one chat: its members and the conversation with the model.
+pub struct ChatContext<B: CompletionBackend> {
+    pub chat_data: ChatData,
+    pub gpt: Gpt<B>,
+}
+
+/// The contexts of the chats in the chats table, each is handled by its own worker.
+pub struct Dispatcher<B: CompletionBackend> {
+    chats: HashMap<ChatId, ChatContext<B>>,
+}
+
+impl<B: CompletionBackend + Clone> Dispatcher<B> {
+    /// Creates the context of every chat in the db, the chats share the backend.
+    pub async fn load(backend: B, db: &Db) -> Result<Self> {
+        let mut chats = HashMap::new();
+        for chat_id in db.list_chats().await? {
+            let context = ChatContext {
+                chat_data: ChatData::with_users(chat_id, db.load_chat_members(chat_id).await?),
+                gpt: Gpt::new(backend.clone(), db, chat_id).await?,
+            };
+            info!("Chat {} has been loaded", chat_id);
+            chats.insert(chat_id, context);
+        }
+
+        if chats.is_empty() {
+            return Err(Error::Config("No chats are set, add one with -c <chat_id>".to_string()));
+        }
+
+        Ok(Self { chats })
+    }
+}
+
+impl<B: CompletionBackend> Dispatcher<B> {
+    /// Starts a worker for every chat, `worker` handles the updates of the chat in the order they are queued.
+    pub fn spawn<F, Fut>(self, worker: F) -> Workers
+        where F: Fn(ChatId, ChatContext<B>, mpsc::Receiver<Update>) -> Fut,
+              Fut: Future<Output = ()> + Send + 'static {
+        let mut senders = HashMap::new();
+        let mut handles = Vec::new();
+        for (chat_id, context) in self.chats {
+            let (sender, receiver) = mpsc::channel(CHAT_QUEUE_SIZE);
+            senders.insert(chat_id, sender);
+            handles.push(tokio::spawn(worker(chat_id, context, receiver)));
+        }
+
+        Workers { senders, handles }
+    }
+}
+
+/// Queues of the chat workers. The queues are in memory and don't survive a restart,
+/// so an update is queued only once it is committed to the inbox, which is resumed on the next start.
+pub struct Workers {
+    senders: HashMap<ChatId, mpsc::Sender<Update>>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Workers {
+    /// Passes the update to the worker of its chat, waiting while the queue of the chat is full.
+    /// Returns `false` if the update is not for any of the chats.
+    pub async fn queue(&self, update: Update) -> bool {
+        let Some(chat_id) = update.chat_id() else { return false; };
+        let Some(sender) = self.senders.get(&chat_id) else { return false; };
+
+        let update = match sender.try_send(update) {
+            Ok(()) => return true,
+            Err(TrySendError::Full(update)) => {
+                warn!("Queue of chat {} is full, waiting for its worker", chat_id);
+                update
+            }
+            Err(TrySendError::Closed(update)) => update,
+        };
+
+        if let Err(e) = sender.send(update).await {
+            error!("worker of chat {} has stopped, update {} is dropped", chat_id, e.0.id);
+        }
+        true
+    }
+
+    /// Closes the queues and waits until the workers have handled what is left in them.
+    pub async fn shutdown(self) {
+        drop(self.senders);
+        for handle in self.handles {
+            if let Err(e) = handle.await {
+                error!("chat worker failed: {:?}", e);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use async_trait::async_trait;
+    use crate::backend::{BackendError, CompletionRequest};
+    use crate::db::{ChatKey, ConfKey};
+    use crate::fixtures::update;
+    use super::*;
+
+    #[derive(Clone)]
+    struct EchoBackend;
+
+    #[async_trait]
+    impl CompletionBackend for EchoBackend {
+        async fn complete(&self, request: CompletionRequest<'_>) -> std::result::Result<String, BackendError> {
+            Ok(request.model.to_string())
+        }
+    }
+
+    async fn db_with_prompt() -> Db {
+        let db = Db::in_memory().await;
+        db.write_conf_value(ConfKey::GptPrompt, Some("prompt".to_string())).await.unwrap();
+        db
+    }
+
+    #[tokio::test]
+    async fn test_no_chats() {
+        let db = db_with_prompt().await;
+
+        assert!(matches!(Dispatcher::load(EchoBackend, &db).await, Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_chats_have_own_settings() {
+        let db = db_with_prompt().await;
+        db.write_conf_value(ConfKey::GptModel, Some("default-model".to_string())).await.unwrap();
+        db.add_chat(ChatId(-1)).await.unwrap();
+        db.add_chat(ChatId(-2)).await.unwrap();
+        db.write_chat_value(ChatId(-2), ChatKey::Model, Some("own-model".to_string())).await.unwrap();
+
+        let dispatcher = Dispatcher::load(EchoBackend, &db).await.unwrap();
+
+        assert_eq!(dispatcher.chats[&ChatId(-1)].gpt.model(), "default-model");
+        assert_eq!(dispatcher.chats[&ChatId(-2)].gpt.model(), "own-model");
+    }
+
+    #[tokio::test]
+    async fn test_settings_of_unknown_chat_are_refused() {
+        let db = db_with_prompt().await;
+        db.add_chat(ChatId(-1)).await.unwrap();
+        db.remove_chat(ChatId(-1)).await.unwrap();
+
+        let result = db.write_chat_value(ChatId(-1), ChatKey::Model, Some("own-model".to_string())).await;
+
+        assert!(matches!(result, Err(Error::Config(_))));
+    }
+
+    #[tokio::test]
+    async fn test_updates_are_queued_per_chat_in_order() {
+        let db = db_with_prompt().await;
+        db.add_chat(ChatId(-1)).await.unwrap();
+        db.add_chat(ChatId(-2)).await.unwrap();
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let workers = Dispatcher::load(EchoBackend, &db).await.unwrap().spawn(|chat_id, _context, mut receiver| {
+            let handled = handled.clone();
+            async move {
+                while let Some(update) = receiver.recv().await {
+                    handled.lock().unwrap().push((chat_id, update.id));
+                }
+            }
+        });
+
+        let mut queued = Vec::new();
+        for (id, chat_id) in [(1, -2), (2, -1), (3, -3), (4, -2)] {
+            queued.push(workers.queue(update(id, chat_id)).await);
+        }
+        workers.shutdown().await;
+
+        assert_eq!(queued, vec![true, true, false, true]);
+        let handled = handled.lock().unwrap();
+        let chat = |chat_id| handled.iter().filter(|(id, _)| *id == ChatId(chat_id)).map(|(_, update_id)| *update_id).collect::<Vec<_>>();
+        assert_eq!(chat(-2), vec![1, 4]);
+        assert_eq!(chat(-1), vec![2]);
+    }
+}
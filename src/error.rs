@@ -0,0 +1,65 @@
+use thiserror::Error;
+use crate::backend::BackendError;
+use crate::trigger::TriggerError;
+
+/// Errors of the bot, one variant per subsystem.
+#[derive(Debug, Error)]
+pub enum Error {
+    /// missing or invalid setting in the environment, the conf table or on the command line
+    #[error("configuration error: {0}")]
+    Config(String),
+    #[error("invalid response triggers: {0}")]
+    Trigger(#[from] TriggerError),
+    #[error("database error: {0}")]
+    Db(#[from] sqlx::Error),
+    /// database schema the migrations can't bring up to date
+    #[error("database schema error: {0}")]
+    Schema(String),
+    #[error("telegram error: {0}")]
+    Telegram(#[from] teloxide::RequestError),
+    #[error("webhook server error: {0}")]
+    Webhook(#[from] hyper::Error),
+    #[error("completion backend error: {0}")]
+    Backend(#[from] BackendError),
+    #[error("JSON error: {0}")]
+    Json(#[from] serde_json::Error),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    /// Process exit code, following the BSD sysexits.h conventions.
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            Error::Config(_) | Error::Trigger(_) => 78, // EX_CONFIG
+            Error::Db(_) => 74, // EX_IOERR
+            Error::Schema(_) => 65, // EX_DATAERR
+            Error::Telegram(_) => 69, // EX_UNAVAILABLE
+            Error::Webhook(_) => 71, // EX_OSERR
+            Error::Backend(_) => 76, // EX_PROTOCOL
+            Error::Json(_) => 65, // EX_DATAERR
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error as _;
+    use super::*;
+
+    #[test]
+    fn test_source_is_chained() {
+        let error = Error::from(BackendError::Permanent("invalid api key".to_string()));
+
+        assert_eq!(error.to_string(), "completion backend error: invalid api key");
+        assert_eq!(error.source().unwrap().to_string(), "invalid api key");
+        assert_eq!(error.exit_code(), 76);
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(Error::Config("Chat id is not set".to_string()).exit_code(), 78);
+        assert_eq!(Error::from(TriggerError::Chance(2.0)).exit_code(), 78);
+        assert_eq!(Error::from(sqlx::Error::PoolClosed).exit_code(), 74);
+    }
+}
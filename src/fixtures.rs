@@ -0,0 +1,19 @@
+//! Updates shared by the tests, parsed from `fixtures/updates`.
+use teloxide::types::{ChatId, Update, UpdateKind};
+
+/// The chat of `fixtures/updates/message.json`.
+pub const CHAT_ID: i64 = -100200;
+
+pub fn parse_update(json: &str) -> Update {
+    serde_json::from_str(json).unwrap()
+}
+
+/// A message update with the given id, sent to the given chat.
+pub fn update(id: i32, chat_id: i64) -> Update {
+    let mut update = parse_update(include_str!("../fixtures/updates/message.json"));
+    update.id = id;
+    if let UpdateKind::Message(message) = &mut update.kind {
+        message.chat.id = ChatId(chat_id);
+    }
+    update
+}
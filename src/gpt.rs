@@ -1,74 +1,392 @@
 use std::collections::VecDeque;
-use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use log::{debug, info};
 use serde::Serialize;
-use openai::chat::{ChatCompletion, ChatCompletionMessage, ChatCompletionMessageRole};
-use teloxide::types::UserId;
+use teloxide::types::{ChatId, MessageId, UserId};
+use thiserror::Error;
+use tokio::sync::mpsc::UnboundedSender;
+use crate::backend::{CompletionBackend, CompletionMessage, CompletionParams, CompletionRequest, Role};
 use crate::chat_data::ChatMember;
+use crate::db::{ChatKey, ConfKey, Db};
+use crate::error::{Error as BotError, Result};
+use crate::tokens;
+use crate::trigger::ResponseTrigger;
 
-pub struct Gpt {
+pub const MAX_HISTORY_CAPACITY: usize = 100;
+
+const SUMMARY_PROMPT: &str = "You maintain the long-term memory of a group chat assistant. \
+Update the summary of the earlier conversation with the messages below. Keep the topics, decisions, \
+open questions and who said what when it matters. Answer with the updated summary only, in a few short paragraphs.";
+const SUMMARY_MAX_TOKENS: u64 = 400;
+
+pub struct Gpt<B: CompletionBackend> {
+    backend: B,
     model: String,
     prompt: String,
+    params: CompletionParams,
+    // overrides the context size derived from the model
+    context_size: Option<usize>,
+    reply_mode: ReplyMode,
+    trigger: ResponseTrigger,
+    preserve_context: bool,
+    summarize: bool,
+    summary: Option<String>,
     messages_capacity: usize,
-    messages: VecDeque<ChatMessage>,
+    messages: VecDeque<HistoryEntry>,
+    // entries dropped from the history and not summarized yet
+    evicted: Vec<HistoryEntry>,
+    // user messages received since the last response
+    pending: usize,
+}
+
+enum HistoryEntry {
+    User(ChatMessage),
+    Assistant(String),
+}
+
+impl HistoryEntry {
+    fn to_completion_message(&self) -> Result<CompletionMessage, serde_json::Error> {
+        Ok(match self {
+            HistoryEntry::User(message) => CompletionMessage {
+                role: Role::User,
+                name: message.user.name.clone(),
+                content: serde_json::to_string(&message.text)?,
+            },
+            HistoryEntry::Assistant(text) => CompletionMessage {
+                role: Role::Assistant,
+                name: None,
+                content: text.clone(),
+            },
+        })
+    }
+}
+
+/// Which message of the history the bot response is sent as a reply to.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReplyMode {
+    /// the last message of the history
+    Last,
+    /// the last message mentioning or replying to the bot, no reply if there is none
+    Addressed,
+    /// responses are not sent as replies
+    None,
 }
 
-impl Gpt {
-    pub fn new(api_key: String, model: String, prompt: String, capacity: usize) -> Result<Self, Box<dyn Error>> {
-        openai::set_key(api_key);
+impl FromStr for ReplyMode {
+    type Err = ParseReplyModeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "last" => Ok(ReplyMode::Last),
+            "addressed" => Ok(ReplyMode::Addressed),
+            "none" => Ok(ReplyMode::None),
+            _ => Err(ParseReplyModeError(s.to_string())),
+        }
+    }
+}
+
+impl Display for ReplyMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplyMode::Last => write!(f, "last"),
+            ReplyMode::Addressed => write!(f, "addressed"),
+            ReplyMode::None => write!(f, "none"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Error)]
+#[error("unknown reply mode '{0}', expected one of: last, addressed, none")]
+pub struct ParseReplyModeError(String);
+
+impl<B: CompletionBackend> Gpt<B> {
+    /// Creates the conversation of the chat, with its own settings where it has them.
+    pub async fn new(backend: B, db: &Db, chat_id: ChatId) -> Result<Self> {
+        let capacity = read_history_capacity(db, chat_id).await?;
+        let prompt = db.read_chat_value::<String>(chat_id, ChatKey::Prompt).await?.ok_or_else(|| BotError::Config("Prompt is not set".to_string()))?;
+        let model = db.read_chat_value::<String>(chat_id, ChatKey::Model).await?.ok_or_else(|| BotError::Config("Model is not set".to_string()))?;
+        let stop = match db.read_conf_value::<String>(ConfKey::GptStop).await? {
+            Some(stop) => serde_json::from_str(&stop)?,
+            None => Vec::new(),
+        };
+        let params = CompletionParams {
+            temperature: db.read_conf_value(ConfKey::GptTemperature).await?,
+            top_p: db.read_conf_value(ConfKey::GptTopP).await?,
+            max_tokens: db.read_conf_value(ConfKey::GptMaxTokens).await?,
+            presence_penalty: db.read_conf_value(ConfKey::GptPresencePenalty).await?,
+            frequency_penalty: db.read_conf_value(ConfKey::GptFrequencyPenalty).await?,
+            stop,
+        };
+
+        let mut gpt = Self::with_params(backend, model, prompt, params, capacity);
+        if let Some(reply_mode) = db.read_conf_value::<ReplyMode>(ConfKey::ReplyMode).await? {
+            gpt.set_reply_mode(reply_mode);
+        }
+        gpt.set_context_size(db.read_conf_value(ConfKey::GptContextSize).await?);
+        gpt.set_trigger(ResponseTrigger::read(db, chat_id).await?);
+        if let Some(preserve_context) = db.read_conf_value::<bool>(ConfKey::PreserveContext).await? {
+            gpt.set_preserve_context(preserve_context);
+        }
+        if let Some(summarize) = db.read_conf_value::<bool>(ConfKey::Summarize).await? {
+            gpt.set_summarize(summarize);
+        }
+        gpt.summary = db.read_chat_value(chat_id, ChatKey::Summary).await?;
+
+        Ok(gpt)
+    }
+
+    fn with_params(backend: B, model: String, prompt: String, params: CompletionParams, capacity: usize) -> Self {
         let messages = VecDeque::with_capacity(capacity * 2);
-        Ok(Self {
+        Self {
+            backend,
             model,
             prompt,
+            params,
+            context_size: None,
+            reply_mode: ReplyMode::Last,
+            trigger: ResponseTrigger::every_n(),
+            preserve_context: false,
+            summarize: false,
+            summary: None,
             messages_capacity: capacity,
             messages,
-        })
+            evicted: Vec::new(),
+            pending: 0,
+        }
+    }
+
+    /// Re-reads the history capacity so that changes in the conf table apply without a restart.
+    pub async fn reload_capacity(&mut self, db: &Db, chat_id: ChatId) -> Result<()> {
+        let capacity = read_history_capacity(db, chat_id).await?;
+        if capacity != self.messages_capacity {
+            info!("History capacity changed from {} to {}", self.messages_capacity, capacity);
+            self.set_capacity(capacity);
+        }
+
+        Ok(())
+    }
+
+    pub fn prompt(&self) -> &str {
+        &self.prompt
+    }
+
+    pub fn set_prompt(&mut self, prompt: String) {
+        self.prompt = prompt;
+    }
+
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    pub fn set_model(&mut self, model: String) {
+        self.model = model;
+    }
+
+    pub fn set_context_size(&mut self, context_size: Option<usize>) {
+        self.context_size = context_size;
     }
 
-    pub async fn query(&mut self, history: Vec<ChatMessage>) -> Result<Option<String>, Box<dyn Error>> {
+    /// Tokens available for the prompt and the history, the rest of the context is left to the completion.
+    fn token_budget(&self, max_tokens: Option<u64>) -> usize {
+        let context_size = self.context_size.unwrap_or_else(|| tokens::context_size(&self.model));
+        let reserve = max_tokens.map_or(tokens::DEFAULT_COMPLETION_RESERVE, |max_tokens| max_tokens as usize);
+        context_size.saturating_sub(reserve)
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.messages_capacity
+    }
+
+    pub fn history_len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// Forgets the history and the summary.
+    pub fn reset(&mut self) {
+        self.messages.clear();
+        self.evicted.clear();
+        self.summary = None;
+        self.pending = 0;
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.messages_capacity = capacity;
+        while self.messages.len() > capacity {
+            self.evict_front();
+        }
+    }
+
+    pub fn set_reply_mode(&mut self, reply_mode: ReplyMode) {
+        self.reply_mode = reply_mode;
+    }
+
+    pub fn set_trigger(&mut self, trigger: ResponseTrigger) {
+        self.trigger = trigger;
+    }
+
+    /// When set, the history including the bot's own replies is kept after a response,
+    /// otherwise every response starts from an empty history.
+    pub fn set_preserve_context(&mut self, preserve_context: bool) {
+        self.preserve_context = preserve_context;
+    }
+
+    /// When set, entries dropped from the history are condensed into a summary sent with every request.
+    pub fn set_summarize(&mut self, summarize: bool) {
+        self.summarize = summarize;
+        if !summarize {
+            self.evicted.clear();
+        }
+    }
+
+    fn push(&mut self, entry: HistoryEntry) {
+        self.messages.push_back(entry);
+        if self.messages.len() > self.messages_capacity {
+            self.evict_front();
+        }
+    }
+
+    fn evict_front(&mut self) {
+        if let Some(entry) = self.messages.pop_front() {
+            self.evict(entry);
+        }
+    }
+
+    fn evict(&mut self, entry: HistoryEntry) {
+        if !self.summarize {
+            return;
+        }
+        self.evicted.push(entry);
+        // summarization keeps failing, don't grow without bounds
+        if self.evicted.len() > MAX_HISTORY_CAPACITY {
+            self.evicted.remove(0);
+        }
+    }
+
+    /// Adds the messages to the history, returns whether the trigger fires and `respond` should be called.
+    pub fn add_messages(&mut self, history: Vec<ChatMessage>) -> bool {
+        self.pending += history.len();
+        let respond = self.trigger.should_respond(&history, self.pending, self.messages_capacity);
         for message in history {
-            self.messages.push_back(message);
-            if self.messages.len() > self.messages_capacity {
-                self.messages.pop_front();
+            self.push(HistoryEntry::User(message));
+        }
+
+        respond
+    }
+
+    /// Requests a completion for the current history. With `deltas` the reply is also streamed into the channel.
+    pub async fn respond(&mut self, deltas: Option<UnboundedSender<String>>) -> Result<String> {
+        let mut messages = self.messages.iter().map(HistoryEntry::to_completion_message).collect::<Result<Vec<_>, _>>()?;
+        let budget = self.token_budget(self.params.max_tokens);
+        let sent = messages.len();
+        let used = tokens::trim_to_budget(&self.model, budget, &self.system_messages(&self.prompt), &mut messages);
+        if messages.len() < sent {
+            debug!("Dropped {} oldest messages to fit {} of {} tokens", sent - messages.len(), used, budget);
+        }
+
+        let request = CompletionRequest {
+            model: &self.model,
+            prompt: &self.prompt,
+            summary: self.summary.as_deref(),
+            messages: &messages,
+            params: &self.params,
+        };
+        let response = match deltas {
+            Some(deltas) => self.backend.complete_stream(request, deltas).await?,
+            None => self.backend.complete(request).await?,
+        };
+
+        self.pending = 0;
+        if self.preserve_context {
+            self.push(HistoryEntry::Assistant(response.clone()));
+        } else {
+            while !self.messages.is_empty() {
+                self.evict_front();
             }
         }
 
-        if self.messages.len() < self.messages_capacity {
+        Ok(response)
+    }
+
+    /// Condenses the evicted entries into the summary once there are as many of them as the history holds.
+    /// Returns the new summary, which should be persisted, or `None` if nothing was summarized.
+    pub async fn summarize(&mut self) -> Result<Option<&str>> {
+        if !self.summarize || self.evicted.len() < self.messages_capacity {
             return Ok(None);
         }
 
-        let mut messages = Vec::with_capacity(self.messages_capacity + 1);
-        messages.push(ChatCompletionMessage {
-            role: ChatCompletionMessageRole::System,
-            name: None,
-            content: self.prompt.clone(),
-        });
+        let mut messages = self.evicted.iter().map(HistoryEntry::to_completion_message).collect::<Result<Vec<_>, _>>()?;
+        let params = CompletionParams {
+            max_tokens: Some(SUMMARY_MAX_TOKENS),
+            ..Default::default()
+        };
+        let budget = self.token_budget(params.max_tokens);
+        tokens::trim_to_budget(&self.model, budget, &self.system_messages(SUMMARY_PROMPT), &mut messages);
 
-        for message in self.messages.iter() {
-            let content = serde_json::to_string(&message.text)?;
-            messages.push(ChatCompletionMessage {
-                role: ChatCompletionMessageRole::User,
-                name: message.user.name.clone(),
-                content,
+        let summary = self.backend.complete(CompletionRequest {
+            model: &self.model,
+            prompt: SUMMARY_PROMPT,
+            summary: self.summary.as_deref(),
+            messages: &messages,
+            params: &params,
+        }).await?;
+
+        info!("Summarized {} evicted history entries", self.evicted.len());
+        self.evicted.clear();
+        Ok(Some(self.summary.insert(summary).as_str()))
+    }
+
+    fn system_messages<'a>(&'a self, prompt: &'a str) -> Vec<&'a str> {
+        let mut system = vec![prompt];
+        system.extend(self.summary.as_deref());
+        system
+    }
+
+    /// The message the response is sent as a reply to, valid between `add_messages` and `respond`.
+    pub fn reply_target(&self) -> Option<MessageId> {
+        let mut user_messages = self.messages.iter().rev()
+            .take(self.pending)
+            .filter_map(|entry| match entry {
+                HistoryEntry::User(message) => Some(message),
+                HistoryEntry::Assistant(_) => None,
             });
+
+        match self.reply_mode {
+            ReplyMode::Last => user_messages.next().map(|m| m.message_id),
+            ReplyMode::Addressed => user_messages.find(|m| m.is_addressed()).map(|m| m.message_id),
+            ReplyMode::None => None,
         }
+    }
+}
 
-        let model = &self.model.clone();
-        let chat = ChatCompletion::builder(model, messages).create().await??;
+pub fn validate_history_capacity(capacity: usize) -> Result<usize, String> {
+    if capacity == 0 || capacity > MAX_HISTORY_CAPACITY {
+        return Err(format!("history capacity must be between 1 and {}", MAX_HISTORY_CAPACITY));
+    }
 
-        let first = chat.choices.first().ok_or("No choices")?;
-        self.messages.clear();
+    Ok(capacity)
+}
 
-        Ok(Some(first.message.content.clone()))
-    }
+async fn read_history_capacity(db: &Db, chat_id: ChatId) -> Result<usize> {
+    let capacity = db.read_chat_value::<usize>(chat_id, ChatKey::HistoryCapacity).await?
+        .ok_or_else(|| BotError::Config("History capacity is not set".to_string()))?;
+    validate_history_capacity(capacity).map_err(BotError::Config)
 }
 
 pub struct ChatMessage {
     pub user: ChatMember,
     pub text: ChatMessageJson,
+    pub message_id: MessageId,
+    pub mentions_bot: bool,
+    pub replies_to_bot: bool,
 }
 
-impl From<&teloxide::types::Message> for ChatMessage {
-    fn from(message: &teloxide::types::Message) -> Self {
+impl ChatMessage {
+    pub fn new(message: &teloxide::types::Message, mentions_bot: bool, replies_to_bot: bool) -> Self {
 
         let name = message.from().map(|user| user.id.to_string());
 
@@ -78,8 +396,19 @@ impl From<&teloxide::types::Message> for ChatMessage {
                 name,
             },
             text: message.into(),
+            message_id: message.id,
+            mentions_bot,
+            replies_to_bot,
         }
     }
+
+    pub fn content(&self) -> &str {
+        &self.text.content
+    }
+
+    pub fn is_addressed(&self) -> bool {
+        self.mentions_bot || self.replies_to_bot
+    }
 }
 
 #[derive(Serialize)]
@@ -106,4 +435,295 @@ impl From<&teloxide::types::Message> for ChatMessageJson {
             content,
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use crate::backend::BackendError;
+    use super::*;
+
+    struct SentRequest {
+        model: String,
+        prompt: String,
+        summary: Option<String>,
+        messages: Vec<CompletionMessage>,
+        params: CompletionParams,
+    }
+
+    struct FakeBackend {
+        requests: Mutex<Vec<SentRequest>>,
+    }
+
+    impl FakeBackend {
+        fn new() -> Self {
+            Self { requests: Mutex::new(Vec::new()) }
+        }
+    }
+
+    #[async_trait]
+    impl CompletionBackend for FakeBackend {
+        async fn complete(&self, request: CompletionRequest<'_>) -> Result<String, BackendError> {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(SentRequest {
+                model: request.model.to_string(),
+                prompt: request.prompt.to_string(),
+                summary: request.summary.map(str::to_string),
+                messages: request.messages.to_vec(),
+                params: request.params.clone(),
+            });
+            Ok(format!("reply {}", requests.len()))
+        }
+    }
+
+    fn new_gpt(capacity: usize) -> Gpt<FakeBackend> {
+        let params = CompletionParams {
+            temperature: Some(0.5),
+            stop: vec!["\n\n".to_string()],
+            ..Default::default()
+        };
+        Gpt::with_params(FakeBackend::new(), "model".to_string(), "prompt".to_string(), params, capacity)
+    }
+
+    fn chat_message(message_id: i32, user_id: u64, text: &str) -> ChatMessage {
+        ChatMessage {
+            user: ChatMember { id: UserId(user_id), name: Some(user_id.to_string()) },
+            text: ChatMessageJson { user_name: format!("User {}", user_id), content: text.to_string() },
+            message_id: MessageId(message_id),
+            mentions_bot: false,
+            replies_to_bot: false,
+        }
+    }
+
+    fn mentioning_bot(message: ChatMessage) -> ChatMessage {
+        ChatMessage { mentions_bot: true, ..message }
+    }
+
+    #[tokio::test]
+    async fn test_trigger_waits_for_capacity() {
+        let mut gpt = new_gpt(2);
+
+        assert!(!gpt.add_messages(vec![chat_message(1, 1, "hi")]));
+        assert!(gpt.backend.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_respond_sends_history_to_backend() {
+        let mut gpt = new_gpt(2);
+
+        assert!(gpt.add_messages(vec![chat_message(1, 1, "hi"), chat_message(2, 2, "hello")]));
+        assert_eq!(gpt.reply_target(), Some(MessageId(2)));
+        assert_eq!(gpt.respond(None).await.unwrap(), "reply 1");
+
+        let requests = gpt.backend.requests.lock().unwrap();
+        let request = &requests[0];
+        assert_eq!(request.model, "model");
+        assert_eq!(request.prompt, "prompt");
+        assert_eq!(request.params.temperature, Some(0.5));
+        assert_eq!(request.params.stop, vec!["\n\n".to_string()]);
+        let messages = &request.messages;
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].role, Role::User);
+        assert_eq!(messages[0].name, Some("1".to_string()));
+        assert_eq!(messages[1].content, r#"{"user_name":"User 2","content":"hello"}"#);
+    }
+
+    #[tokio::test]
+    async fn test_reply_to_addressed_message() {
+        let mut gpt = new_gpt(3);
+        gpt.set_reply_mode(ReplyMode::Addressed);
+
+        assert!(gpt.add_messages(vec![mentioning_bot(chat_message(1, 1, "@bot hi")), chat_message(2, 2, "hello"), chat_message(3, 3, "hey")]));
+        assert_eq!(gpt.reply_target(), Some(MessageId(1)));
+        gpt.respond(None).await.unwrap();
+
+        assert!(gpt.add_messages(vec![chat_message(4, 1, "one"), chat_message(5, 2, "two"), chat_message(6, 3, "three")]));
+        assert_eq!(gpt.reply_target(), None);
+    }
+
+    #[tokio::test]
+    async fn test_no_reply() {
+        let mut gpt = new_gpt(1);
+        gpt.set_reply_mode(ReplyMode::None);
+
+        assert!(gpt.add_messages(vec![mentioning_bot(chat_message(1, 1, "@bot hi"))]));
+
+        assert_eq!(gpt.reply_target(), None);
+    }
+
+    #[tokio::test]
+    async fn test_history_is_cleared_after_response() {
+        let mut gpt = new_gpt(2);
+
+        assert!(gpt.add_messages(vec![chat_message(1, 1, "one"), chat_message(2, 1, "two")]));
+        gpt.respond(None).await.unwrap();
+
+        assert!(!gpt.add_messages(vec![chat_message(3, 1, "three")]));
+        assert_eq!(gpt.history_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_preserved_context_includes_assistant_replies() {
+        let mut gpt = new_gpt(3);
+        gpt.set_preserve_context(true);
+
+        assert!(gpt.add_messages(vec![chat_message(1, 1, "one"), chat_message(2, 1, "two"), chat_message(3, 1, "three")]));
+        gpt.respond(None).await.unwrap();
+        assert!(!gpt.add_messages(vec![chat_message(4, 2, "four"), chat_message(5, 2, "five")]));
+
+        assert!(gpt.add_messages(vec![chat_message(6, 2, "six")]));
+        assert_eq!(gpt.reply_target(), Some(MessageId(6)));
+        gpt.respond(None).await.unwrap();
+
+        let requests = gpt.backend.requests.lock().unwrap();
+        assert_eq!(requests.len(), 2);
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 3);
+        assert!(messages[0].content.contains("four"));
+        assert!(messages[2].content.contains("six"));
+
+        drop(requests);
+        assert_eq!(gpt.history_len(), 3);
+        assert!(matches!(gpt.messages.back(), Some(HistoryEntry::Assistant(text)) if text == "reply 2"));
+    }
+
+    #[tokio::test]
+    async fn test_assistant_replies_are_sent_with_assistant_role() {
+        let mut gpt = new_gpt(5);
+        gpt.set_preserve_context(true);
+        gpt.set_trigger(ResponseTrigger::parse("mention", None, None).unwrap());
+
+        assert!(gpt.add_messages(vec![mentioning_bot(chat_message(1, 1, "hi @bot"))]));
+        gpt.respond(None).await.unwrap();
+        assert!(gpt.add_messages(vec![mentioning_bot(chat_message(2, 1, "and? @bot"))]));
+        gpt.respond(None).await.unwrap();
+
+        let requests = gpt.backend.requests.lock().unwrap();
+        let messages = &requests[1].messages;
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1].role, Role::Assistant);
+        assert_eq!(messages[1].content, "reply 1");
+        assert_eq!(messages[1].name, None);
+    }
+
+    #[tokio::test]
+    async fn test_respond_on_mention() {
+        let mut gpt = new_gpt(5);
+        gpt.set_trigger(ResponseTrigger::parse("every,mention", None, None).unwrap());
+
+        assert!(!gpt.add_messages(vec![chat_message(1, 1, "hi")]));
+        assert!(gpt.add_messages(vec![mentioning_bot(chat_message(2, 2, "hey @bot"))]));
+        gpt.respond(None).await.unwrap();
+
+        let requests = gpt.backend.requests.lock().unwrap();
+        assert_eq!(requests[0].messages.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_respond_trims_history_to_token_budget() {
+        let mut gpt = new_gpt(3);
+        gpt.params.max_tokens = Some(100);
+        gpt.set_context_size(Some(200));
+
+        gpt.add_messages(vec![chat_message(1, 1, &"long ".repeat(100)), chat_message(2, 1, "two"), chat_message(3, 1, "three")]);
+        gpt.respond(None).await.unwrap();
+
+        let requests = gpt.backend.requests.lock().unwrap();
+        let messages = &requests[0].messages;
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].content.contains("two"));
+    }
+
+    #[tokio::test]
+    async fn test_evicted_history_is_summarized() {
+        let mut gpt = new_gpt(2);
+        gpt.set_preserve_context(true);
+        gpt.set_summarize(true);
+
+        assert!(gpt.add_messages(vec![chat_message(1, 1, "one"), chat_message(2, 1, "two"), chat_message(3, 1, "three")]));
+        gpt.respond(None).await.unwrap();
+        assert_eq!(gpt.summarize().await.unwrap(), Some("reply 2"));
+        assert_eq!(gpt.summarize().await.unwrap(), None);
+        assert!(gpt.add_messages(vec![chat_message(4, 2, "four"), chat_message(5, 2, "five")]));
+        gpt.respond(None).await.unwrap();
+
+        let requests = gpt.backend.requests.lock().unwrap();
+        let summary_request = &requests[1];
+        assert_eq!(summary_request.prompt, SUMMARY_PROMPT);
+        assert_eq!(summary_request.summary, None);
+        assert_eq!(summary_request.params.max_tokens, Some(SUMMARY_MAX_TOKENS));
+        assert_eq!(summary_request.messages.len(), 2);
+        assert!(summary_request.messages[0].content.contains("one"));
+        assert_eq!(summary_request.messages[1].content, r#"{"user_name":"User 1","content":"two"}"#);
+        assert_eq!(requests[2].summary, Some("reply 2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_history_is_not_summarized_by_default() {
+        let mut gpt = new_gpt(1);
+
+        assert!(gpt.add_messages(vec![chat_message(1, 1, "one"), chat_message(2, 1, "two")]));
+        gpt.respond(None).await.unwrap();
+
+        assert_eq!(gpt.summarize().await.unwrap(), None);
+        assert!(gpt.evicted.is_empty());
+        assert_eq!(gpt.backend.requests.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_streamed_response() {
+        let mut gpt = new_gpt(1);
+        gpt.set_preserve_context(true);
+        let (deltas, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        assert!(gpt.add_messages(vec![mentioning_bot(chat_message(1, 1, "hi @bot"))]));
+        assert_eq!(gpt.reply_target(), Some(MessageId(1)));
+        let text = gpt.respond(Some(deltas)).await.unwrap();
+
+        assert_eq!(text, "reply 1");
+        assert_eq!(receiver.recv().await, Some("reply 1".to_string()));
+        assert_eq!(receiver.recv().await, None);
+        assert!(matches!(gpt.messages.back(), Some(HistoryEntry::Assistant(text)) if text == "reply 1"));
+    }
+
+    #[test]
+    fn test_parse_reply_mode() {
+        assert_eq!("last".parse::<ReplyMode>(), Ok(ReplyMode::Last));
+        assert_eq!("addressed".parse::<ReplyMode>(), Ok(ReplyMode::Addressed));
+        assert_eq!("none".parse::<ReplyMode>(), Ok(ReplyMode::None));
+        assert!("first".parse::<ReplyMode>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_set_capacity_trims_history() {
+        let mut gpt = new_gpt(3);
+
+        gpt.add_messages(vec![chat_message(1, 1, "one"), chat_message(2, 1, "two")]);
+        gpt.set_capacity(1);
+
+        assert_eq!(gpt.messages.len(), 1);
+        assert!(matches!(&gpt.messages[0], HistoryEntry::User(message) if message.content() == "two"));
+    }
+
+    #[test]
+    fn test_validate_history_capacity() {
+        assert!(validate_history_capacity(0).is_err());
+        assert!(validate_history_capacity(MAX_HISTORY_CAPACITY + 1).is_err());
+        assert_eq!(validate_history_capacity(15), Ok(15));
+    }
+
+    #[tokio::test]
+    async fn test_respond_keeps_last_messages_only() {
+        let mut gpt = new_gpt(2);
+
+        assert!(gpt.add_messages(vec![chat_message(1, 1, "one"), chat_message(2, 1, "two"), chat_message(3, 1, "three")]));
+        gpt.respond(None).await.unwrap();
+
+        let requests = gpt.backend.requests.lock().unwrap();
+        let messages = &requests[0].messages;
+        assert_eq!(messages.len(), 2);
+        assert!(messages[0].content.contains("two"));
+        assert!(messages[1].content.contains("three"));
+    }
+}
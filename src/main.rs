@@ -1,63 +1,216 @@
 use clap::Parser;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use std::collections::HashSet;
 use std::env;
-use std::error::Error;
+use std::fmt::Display;
+use std::process::ExitCode;
 use std::sync::Arc;
-use std::sync::atomic::AtomicBool;
 use std::time::Duration;
-use teloxide::dispatching::dialogue::GetChatId;
 use teloxide::prelude::*;
 use teloxide::types::{UpdateKind};
-use tokio::time::sleep;
+use tokio::sync::mpsc;
+use tokio::time::{sleep, Instant};
+use tokio_util::sync::CancellationToken;
 use gpt::Gpt;
-use crate::db::{ConfKey, Db};
-use crate::gpt::{ChatMessage};
+use crate::backend::{CompletionBackend, OpenAiBackend, RetryingBackend, RetryPolicy};
+use crate::db::{ChatKey, ConfKey, Db, InboxState};
+use crate::gpt::{ChatMessage, ReplyMode};
 use crate::tg::TgBot;
-use crate::chat_data::{ChatData, ChatMember};
+use crate::trigger::ResponseTrigger;
+use crate::webhook::WebhookConfig;
+use crate::chat_data::ChatMember;
+use crate::commands::Command;
+use crate::dispatcher::{ChatContext, Dispatcher, Workers};
+use crate::error::{Error, Result};
+use crate::pipeline::{Pipeline, Stage};
 
+mod backend;
 mod db;
 mod gpt;
 mod tg;
+mod trigger;
 mod chat_data;
+mod chunker;
+mod commands;
+mod dispatcher;
+mod error;
+#[cfg(test)]
+mod fixtures;
+mod pipeline;
+mod tokens;
+mod webhook;
 
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn Error>> {
-    dotenvy::dotenv()?;
+async fn main() -> ExitCode {
+    // the logger is configured from .env, so this error can only be printed
+    if let Err(e) = dotenvy::dotenv() {
+        let e = Error::Config(format!("Couldn't load .env: {}", e));
+        eprintln!("{}", e);
+        return ExitCode::from(e.exit_code());
+    }
     env_logger::init();
 
     match run().await {
         Ok(_) => {
             info!("Exit");
-            Ok(())
+            ExitCode::SUCCESS
         }
         Err(e) => {
-            error!("Stopped with error: {:?}", e);
-            Err(e)
+            error!("Stopped with error: {}", e);
+            ExitCode::from(e.exit_code())
         }
     }
 }
 
 #[derive(Parser)]
 struct Cli {
-    /// Set telegram Chat Id
+    /// Add telegram chat, the capacity, model and trigger settings given along with it apply to this chat only
     #[arg(short, value_name = "chat_id")]
     chat_id: Option<i64>,
 
+    /// Remove telegram chat
+    #[arg(long, value_name = "chat_id")]
+    remove_chat: Option<i64>,
+
+    /// List telegram chats
+    #[arg(long)]
+    list_chats: bool,
+
     /// Set the user as bot admin
     #[arg(short('a'), value_name = "user_id")]
     set_bot_admin: Option<u64>,
 
+    /// Revoke bot admin from the user
+    #[arg(short('r'), value_name = "user_id")]
+    revoke_bot_admin: Option<u64>,
+
+    /// List user permissions
+    #[arg(short('l'))]
+    list_permissions: bool,
+
     /// Reset telegram update offset
     #[arg(short('o'))]
     reset_offset: bool,
+
+    /// Print the database schema version and the pending migrations, then exit without applying them
+    #[arg(long)]
+    schema_version: bool,
+
+    /// Set the number of chat messages sent to GPT
+    #[arg(short('n'), long, value_name = "capacity", value_parser = parse_history_capacity)]
+    history_capacity: Option<usize>,
+
+    /// Set GPT model
+    #[arg(long, value_name = "model")]
+    model: Option<String>,
+
+    /// Set sampling temperature, between 0 and 2
+    #[arg(long, value_name = "temperature", value_parser = parse_temperature)]
+    temperature: Option<f32>,
+
+    /// Set nucleus sampling probability mass, between 0 and 1
+    #[arg(long, value_name = "top_p", value_parser = parse_top_p)]
+    top_p: Option<f32>,
+
+    /// Set maximum number of tokens in the completion
+    #[arg(long, value_name = "max_tokens")]
+    max_tokens: Option<u64>,
+
+    /// Set presence penalty, between -2 and 2
+    #[arg(long, value_name = "penalty", value_parser = parse_penalty)]
+    presence_penalty: Option<f32>,
+
+    /// Set frequency penalty, between -2 and 2
+    #[arg(long, value_name = "penalty", value_parser = parse_penalty)]
+    frequency_penalty: Option<f32>,
+
+    /// Set which message the bot replies to: last, addressed or none
+    #[arg(long, value_name = "mode")]
+    reply_mode: Option<ReplyMode>,
+
+    /// Set when the bot responds, comma separated: every (history is full), mention, reply
+    #[arg(long, value_name = "triggers")]
+    triggers: Option<String>,
+
+    /// Set regex making the bot respond to matching messages
+    #[arg(long, value_name = "regex")]
+    trigger_keywords: Option<String>,
+
+    /// Set probability to respond to any message, between 0 and 1
+    #[arg(long, value_name = "chance", value_parser = parse_trigger_chance)]
+    trigger_chance: Option<f64>,
+
+    /// Set whether the conversation history, including bot replies, is kept after a response
+    #[arg(long, value_name = "bool")]
+    preserve_context: Option<bool>,
+
+    /// Set whether messages dropped from the history are summarized into a long-term memory
+    #[arg(long, value_name = "bool")]
+    summarize: Option<bool>,
+
+    /// Set whether replies are streamed by editing the message as the completion is generated
+    #[arg(long, value_name = "bool")]
+    streaming: Option<bool>,
+
+    /// Set the message sent when no completion could be obtained, by default nothing is sent
+    #[arg(long, value_name = "text")]
+    fallback_message: Option<String>,
+
+    /// Set the model context size in tokens, by default derived from the model name
+    #[arg(long, value_name = "tokens")]
+    context_size: Option<usize>,
+
+    /// Set stop sequences, up to 4, can be repeated
+    #[arg(long, value_name = "sequence")]
+    stop: Vec<String>,
+}
+
+fn parse_history_capacity(s: &str) -> Result<usize, String> {
+    let value = s.parse::<usize>().map_err(|e| e.to_string())?;
+    gpt::validate_history_capacity(value)
+}
+
+fn parse_trigger_chance(s: &str) -> Result<f64, String> {
+    let value = s.parse::<f64>().map_err(|e| e.to_string())?;
+    trigger::validate_chance(value).map_err(|e| e.to_string())
+}
+
+fn parse_temperature(s: &str) -> Result<f32, String> {
+    parse_f32_in_range(s, 0.0, 2.0)
+}
+
+fn parse_top_p(s: &str) -> Result<f32, String> {
+    parse_f32_in_range(s, 0.0, 1.0)
+}
+
+fn parse_penalty(s: &str) -> Result<f32, String> {
+    parse_f32_in_range(s, -2.0, 2.0)
 }
 
-async fn run() -> Result<(), Box<dyn Error>> {
+fn parse_f32_in_range(s: &str, min: f32, max: f32) -> Result<f32, String> {
+    let value = s.parse::<f32>().map_err(|e| e.to_string())?;
+    if value < min || value > max {
+        return Err(format!("value must be between {} and {}", min, max));
+    }
+
+    Ok(value)
+}
+
+async fn run() -> Result<()> {
     let cli = Cli::parse();
 
     info!("Starting...");
     let db = get_env("DB")?;
     let db = Db::new(db).await?;
+
+    if cli.schema_version {
+        println!("Schema version: {}", db.schema_version().await?);
+        for migration in db.pending_migrations().await? {
+            println!("Pending migration: {}", migration);
+        }
+        return Ok(());
+    }
+
     db.migrate().await?;
 
     if cli.reset_offset {
@@ -65,89 +218,467 @@ async fn run() -> Result<(), Box<dyn Error>> {
         db.write_conf_value::<String>(ConfKey::Offset, None).await?;
     }
 
-    if let Some(chat_id) = cli.chat_id {
-        info!("Setting telegram chat id to {}...", chat_id);
-        db.write_conf_value(ConfKey::ChatId, Some(chat_id.to_string())).await?;
+    let chat_id = cli.chat_id.map(ChatId);
+    if let Some(chat_id) = chat_id {
+        info!("Adding telegram chat {}...", chat_id);
+        db.add_chat(chat_id).await?;
+    }
+
+    if let Some(chat_id) = cli.remove_chat {
+        info!("Removing telegram chat {}...", chat_id);
+        db.remove_chat(ChatId(chat_id)).await?;
+    }
+
+    if cli.list_chats {
+        for chat_id in db.list_chats().await? {
+            println!("chat {}", chat_id);
+        }
     }
 
     if let Some(user_id) = cli.set_bot_admin {
         info!("Setting user {} as bot admin...", user_id);
-        db.set_bot_admin(user_id).await?;
+        db.set_bot_admin(UserId(user_id), true).await?;
+    }
+
+    if let Some(user_id) = cli.revoke_bot_admin {
+        info!("Revoking bot admin from user {}...", user_id);
+        db.set_bot_admin(UserId(user_id), false).await?;
+    }
+
+    if cli.list_permissions {
+        for permissions in db.list_permissions().await? {
+            println!("{}", permissions);
+        }
+    }
+
+    if let Some(capacity) = cli.history_capacity {
+        info!("Setting history capacity to {}...", capacity);
+        write_setting(&db, chat_id, ChatKey::HistoryCapacity, ConfKey::HistoryCapacity, capacity.to_string()).await?;
+    }
+
+    if let Some(model) = cli.model {
+        info!("Setting GPT model to {}...", model);
+        write_setting(&db, chat_id, ChatKey::Model, ConfKey::GptModel, model).await?;
+    }
+
+    if let Some(temperature) = cli.temperature {
+        info!("Setting GPT temperature to {}...", temperature);
+        db.write_conf_value(ConfKey::GptTemperature, Some(temperature.to_string())).await?;
     }
 
-    let prompt = db.read_conf_value::<String>(ConfKey::GptPrompt).await?.ok_or("Prompt is not set")?;
-    let history_capacity = 15; //db.read_conf_value::<usize>(ConfKey::HistoryCapacity).await?.ok_or("History capacity is not set")?;
+    if let Some(top_p) = cli.top_p {
+        info!("Setting GPT top_p to {}...", top_p);
+        db.write_conf_value(ConfKey::GptTopP, Some(top_p.to_string())).await?;
+    }
+
+    if let Some(max_tokens) = cli.max_tokens {
+        info!("Setting GPT max tokens to {}...", max_tokens);
+        db.write_conf_value(ConfKey::GptMaxTokens, Some(max_tokens.to_string())).await?;
+    }
+
+    if let Some(summarize) = cli.summarize {
+        info!("Setting history summarization to {}...", summarize);
+        db.write_conf_value(ConfKey::Summarize, Some(summarize.to_string())).await?;
+    }
+
+    if let Some(streaming) = cli.streaming {
+        info!("Setting streaming to {}...", streaming);
+        db.write_conf_value(ConfKey::Streaming, Some(streaming.to_string())).await?;
+    }
+
+    if let Some(fallback) = cli.fallback_message {
+        info!("Setting fallback message to '{}'...", fallback);
+        db.write_conf_value(ConfKey::FallbackMessage, Some(fallback)).await?;
+    }
+
+    if let Some(context_size) = cli.context_size {
+        info!("Setting GPT context size to {}...", context_size);
+        db.write_conf_value(ConfKey::GptContextSize, Some(context_size.to_string())).await?;
+    }
+
+    if let Some(penalty) = cli.presence_penalty {
+        info!("Setting GPT presence penalty to {}...", penalty);
+        db.write_conf_value(ConfKey::GptPresencePenalty, Some(penalty.to_string())).await?;
+    }
+
+    if let Some(penalty) = cli.frequency_penalty {
+        info!("Setting GPT frequency penalty to {}...", penalty);
+        db.write_conf_value(ConfKey::GptFrequencyPenalty, Some(penalty.to_string())).await?;
+    }
+
+    if let Some(reply_mode) = cli.reply_mode {
+        info!("Setting reply mode to {}...", reply_mode);
+        db.write_conf_value(ConfKey::ReplyMode, Some(reply_mode.to_string())).await?;
+    }
+
+    if cli.triggers.is_some() || cli.trigger_keywords.is_some() {
+        // validate the combination before storing it
+        ResponseTrigger::parse(cli.triggers.as_deref().unwrap_or_default(), cli.trigger_keywords.as_deref(), None)?;
+    }
+
+    if let Some(triggers) = cli.triggers {
+        info!("Setting response triggers to {}...", triggers);
+        write_setting(&db, chat_id, ChatKey::Triggers, ConfKey::Triggers, triggers).await?;
+    }
+
+    if let Some(keywords) = cli.trigger_keywords {
+        info!("Setting response trigger keywords to {}...", keywords);
+        write_setting(&db, chat_id, ChatKey::TriggerKeywords, ConfKey::TriggerKeywords, keywords).await?;
+    }
+
+    if let Some(chance) = cli.trigger_chance {
+        info!("Setting response trigger chance to {}...", chance);
+        write_setting(&db, chat_id, ChatKey::TriggerChance, ConfKey::TriggerChance, chance.to_string()).await?;
+    }
+
+    if let Some(preserve_context) = cli.preserve_context {
+        info!("Setting preserve context to {}...", preserve_context);
+        db.write_conf_value(ConfKey::PreserveContext, Some(preserve_context.to_string())).await?;
+    }
+
+    if !cli.stop.is_empty() {
+        if cli.stop.len() > 4 {
+            return Err(Error::Config("Up to 4 stop sequences are allowed".to_string()));
+        }
+        info!("Setting GPT stop sequences to {:?}...", cli.stop);
+        db.write_conf_value(ConfKey::GptStop, Some(serde_json::to_string(&cli.stop)?)).await?;
+    }
 
     let openai_key = get_env("OPENAI_KEY")?;
-    let gpt = Gpt::new(openai_key, "gpt-3.5-turbo-0301".to_string(), prompt, history_capacity)?; //TODO: make model configurable
+    let openai_base_url = get_env("OPENAI_BASE_URL").ok();
+    let backend = RetryingBackend::new(OpenAiBackend::new(openai_key, openai_base_url), RetryPolicy::default());
+    let dispatcher = Dispatcher::load(Arc::new(backend), &db).await?;
 
     let token = get_env("TG_TOKEN")?;
-    let tg_lp_timeout = get_env("TG_LONGPOOL_TIMEOUT").unwrap_or("10".to_string()).parse::<u32>()?;
+    let tg_lp_timeout = get_env("TG_LONGPOOL_TIMEOUT").unwrap_or("10".to_string()).parse::<u32>().map_err(invalid_env("TG_LONGPOOL_TIMEOUT"))?;
     info!("Telegram long polling timeout has been set to {} seconds", tg_lp_timeout);
-    let tg_retry_timeout = get_env("TG_RETRY_TIMEOUT").unwrap_or("5".to_string()).parse::<u64>()?;
+    let tg_retry_timeout = get_env("TG_RETRY_TIMEOUT").unwrap_or("5".to_string()).parse::<u64>().map_err(invalid_env("TG_RETRY_TIMEOUT"))?;
     let tg_retry_timeout = Duration::from_secs(tg_retry_timeout);
     info!("Telegram retry timeout has been set to {} seconds", tg_retry_timeout.as_secs());
-    let chat_id = db.read_conf_value::<String>(ConfKey::ChatId).await?.ok_or("Chat id is not set")?;
-    let chat_id = chat_id.parse::<i64>()?;
-    let tg_bot = TgBot::new(token, tg_lp_timeout).await?;
 
-    let exit_condition = Arc::new(AtomicBool::new(false)); //TODO: use cancellation token instead
-    futures_util::try_join!(
-        process_messages(ChatId(chat_id), tg_bot, db, gpt, exit_condition.clone(), tg_retry_timeout),
-    )?;
+    let cancellation_token = CancellationToken::new();
+    tokio::spawn(cancel_on_shutdown_signal(cancellation_token.clone()));
+
+    // read before the webhook listener starts, so an update it commits meanwhile isn't resumed as well
+    let unfinished = unfinished_updates(&db).await?;
+
+    let tg_bot = match get_env("TG_WEBHOOK_URL").ok() {
+        Some(url) => {
+            let config = WebhookConfig {
+                url: url.parse().map_err(invalid_env("TG_WEBHOOK_URL"))?,
+                address: get_env("TG_WEBHOOK_ADDR").unwrap_or("0.0.0.0:8080".to_string()).parse().map_err(invalid_env("TG_WEBHOOK_ADDR"))?,
+                secret_token: get_env("TG_WEBHOOK_SECRET")?,
+            };
+            info!("Receiving telegram updates via webhook {}", config.url);
+            TgBot::with_webhook(token, tg_lp_timeout, config, db.clone(), cancellation_token.clone()).await?
+        }
+        None => {
+            info!("Receiving telegram updates via long polling");
+            TgBot::new(token, tg_lp_timeout).await?
+        }
+    };
+
+    let tg_bot = Arc::new(tg_bot);
+    let workers = {
+        let tg_bot = tg_bot.clone();
+        let db = db.clone();
+        dispatcher.spawn(move |chat_id, context, receiver| run_chat_worker(chat_id, context, receiver, tg_bot.clone(), db.clone()))
+    };
+    process_messages(&tg_bot, &db, workers, unfinished, cancellation_token, tg_retry_timeout).await?;
+
+    db.close().await;
 
     Ok(())
 }
 
-async fn process_messages(chat_id: ChatId, tg_bot: TgBot, db: Db, mut gpt: Gpt, exit_trigger: Arc<AtomicBool>, retry_timeout: Duration) -> Result<(), Box<dyn Error>> {
-    let mut chat_data = ChatData::new(chat_id);
+async fn cancel_on_shutdown_signal(cancellation_token: CancellationToken) {
+    match shutdown_signal().await {
+        Ok(_) => info!("Shutdown signal received, finishing current batch..."),
+        Err(e) => error!("Couldn't listen for shutdown signals: {:?}", e),
+    }
+
+    cancellation_token.cancel();
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() -> std::io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut sigterm = signal(SignalKind::terminate())?;
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result,
+        _ = sigterm.recv() => Ok(()),
+    }
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() -> std::io::Result<()> {
+    tokio::signal::ctrl_c().await
+}
+
+/// How long processed updates stay in the inbox, a redelivered update is recognized meanwhile.
+const INBOX_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+const INBOX_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Updates committed before the last stop, but not handled by then.
+async fn unfinished_updates(db: &Db) -> Result<Vec<Update>> {
+    // a claimed update may have been responded to before the last stop, it is not handled twice
+    let interrupted = db.fail_interrupted_updates().await?;
+    if interrupted > 0 {
+        warn!("{} updates were interrupted while processing and are marked as failed", interrupted);
+    }
+
+    Ok(db.received_updates().await?)
+}
+
+/// Polls updates and queues them for the chat workers, a slow chat doesn't hold up the others.
+async fn process_messages(tg_bot: &TgBot, db: &Db, workers: Workers, unfinished: Vec<Update>, cancellation_token: CancellationToken, retry_timeout: Duration) -> Result<()> {
     let mut offset = db.read_conf_value(ConfKey::Offset).await?;
+    let pipeline = Pipeline::new(db);
 
-    while !exit_trigger.load(std::sync::atomic::Ordering::SeqCst) { //TODO: use cancellation token instead
-        match tg_bot.get_updates(offset).await {
-            Ok(updates) => {
-                db.save_updates(&updates).await?; // TODO: add feature flag for enable/disable saving updates
+    if !unfinished.is_empty() {
+        info!("Resuming {} unfinished updates...", unfinished.len());
+    }
+    for update in unfinished {
+        let update_id = update.id;
+        if !workers.queue(update).await {
+            db.set_update_state(update_id, InboxState::Failed, Some("the chat is not served anymore")).await?;
+        }
+    }
+
+    let mut pruned_at = None;
+    while !cancellation_token.is_cancelled() {
+        if pruned_at.map_or(true, |at: Instant| at.elapsed() >= INBOX_PRUNE_INTERVAL) {
+            if let Some(pruned) = pipeline.run(Stage::Prune, None, || db.prune_inbox(INBOX_RETENTION)).await {
+                debug!("pruned {} processed updates from the inbox", pruned);
+            }
+            pruned_at = Some(Instant::now());
+        }
 
+        // only the long poll is interrupted, a received batch is always processed and its offset saved
+        let updates = tokio::select! {
+            _ = cancellation_token.cancelled() => break,
+            updates = tg_bot.get_updates(offset) => updates,
+        };
+
+        match updates {
+            Ok(mut updates) => {
                 for update in &updates {
                     debug!("Update: {:?}", update);
-
-                    if update.chat_id() == Some(chat_id) {
-                        if let Some(user) = ChatMember::try_from(update).ok() {
-                            chat_data.update_user(user);
-                        }
-                    }
+                    // TODO: add feature flag for enable/disable saving updates
+                    pipeline.run(Stage::SaveUpdate, Some(update), || db.save_update(update)).await;
                 }
 
-                let chat_updates = get_chat_updates(&updates, chat_id);
-                if let Some(response) = gpt.query(chat_updates).await? {
-                    debug!("Response: {}", response);
-                    tg_bot.send_message(chat_id, &response).await?;
+                // an empty batch keeps the offset
+                let Some(last) = updates.last() else { continue; };
+                if tg_bot.is_polling() {
+                    let next_offset = Some(last.id + 1);
+                    let batch = updates.iter().collect::<Vec<_>>();
+                    // a batch which couldn't be committed is polled again, so it isn't queued
+                    let Some(new_ids) = pipeline.run(Stage::Inbox, None, || db.receive_updates(&batch, next_offset)).await else { continue; };
+                    offset = next_offset;
+                    // updates polled again are queued already or handled
+                    updates.retain(|update| new_ids.contains(&update.id));
                 }
 
-                offset = updates.last().map_or(None, |u| Some(u.id + 1));
-                db.write_conf_value(ConfKey::Offset, offset).await?;
+                for update in updates {
+                    let update_ids = [update.id];
+                    // updates of other chats have nothing left to do
+                    if !workers.queue(update).await {
+                        pipeline.run(Stage::Processed, None, || db.mark_processed(&update_ids)).await;
+                    }
+                }
             }
             Err(e) => {
                 error!("error getting updates from tg: {:?}", e);
-                sleep(retry_timeout).await;
+                tokio::select! {
+                    _ = cancellation_token.cancelled() => {},
+                    _ = sleep(retry_timeout) => {},
+                }
             }
         }
     }
 
+    info!("polling stopped, waiting for the chat workers...");
+    workers.shutdown().await;
     info!("processing messages stopped");
     Ok(())
 }
 
-fn get_chat_updates(tg_updates: &Vec<Update>, chat_id: ChatId) -> Vec<ChatMessage> {
+/// Handles the updates of one chat in order. Updates queued while the worker was busy are handled together.
+async fn run_chat_worker<B: CompletionBackend>(chat_id: ChatId, mut context: ChatContext<B>, mut receiver: mpsc::Receiver<Update>, tg_bot: Arc<TgBot>, db: Db) {
+    let pipeline = Pipeline::new(&db);
+    while let Some(update) = receiver.recv().await {
+        let mut updates = vec![update];
+        while let Ok(update) = receiver.try_recv() {
+            updates.push(update);
+        }
+
+        let update_ids = updates.iter().map(|update| update.id).collect::<Vec<_>>();
+        // an unclaimed batch stays received and is handled on the next start
+        let Some(claimed) = pipeline.run(Stage::Claim, None, || db.claim_updates(&update_ids)).await else { continue; };
+        // updates delivered again after they were claimed are handled once
+        let mut claimed = claimed.into_iter().collect::<HashSet<_>>();
+        let updates = updates.iter().filter(|update| claimed.remove(&update.id)).collect::<Vec<_>>();
+        if updates.is_empty() {
+            continue;
+        }
+
+        let update_ids = updates.iter().map(|update| update.id).collect::<Vec<_>>();
+        process_chat_updates(chat_id, &mut context, &updates, &tg_bot, &db, &pipeline).await;
+        pipeline.run(Stage::Processed, None, || db.mark_processed(&update_ids)).await;
+    }
+
+    debug!("worker of chat {} stopped", chat_id);
+}
+
+/// Handles the updates of one chat: saves its members, executes commands and responds.
+async fn process_chat_updates<B: CompletionBackend>(chat_id: ChatId, chat: &mut ChatContext<B>, updates: &[&Update], tg_bot: &TgBot, db: &Db, pipeline: &Pipeline<'_>) {
+    let ChatContext { chat_data, gpt } = chat;
+
+    for update in updates {
+        if let Ok(user) = chat_data::get_user(update) {
+            let result = chat_data.update_user(ChatMember::from(user));
+            let is_message = matches!(update.kind, UpdateKind::Message(_));
+            pipeline.run(Stage::SaveUser, Some(update), || db.save_user(chat_id, user, is_message, &result)).await;
+        }
+    }
+
+    pipeline.check(Stage::Settings, None, gpt.reload_capacity(db, chat_id).await).await;
+    for (update, message) in get_chat_messages(updates) {
+        if let Some(command) = Command::from_message(message, tg_bot.username()) {
+            let result = handle_command(command, message, db, gpt).await;
+            // a command which has run isn't dead-lettered because its reply couldn't be sent
+            if let Some(reply) = pipeline.check(Stage::Command, Some(update), result).await {
+                pipeline.run(Stage::Send, Some(update), || tg_bot.send_message(message.chat.id, &reply, Some(message.id))).await;
+            }
+        }
+    }
+
+    let paused = pipeline.run(Stage::Settings, None, || db.read_chat_value::<bool>(chat_id, ChatKey::Paused)).await.flatten().unwrap_or(false);
+    if paused {
+        return;
+    }
+
+    let chat_updates = get_chat_updates(updates, tg_bot);
+    if gpt.add_messages(chat_updates) {
+        let streaming = pipeline.run(Stage::Settings, None, || db.read_conf_value::<bool>(ConfKey::Streaming)).await.flatten().unwrap_or(false);
+        let fallback = pipeline.run(Stage::Settings, None, || db.read_conf_value::<String>(ConfKey::FallbackMessage)).await.flatten();
+        // the message the response is for, kept in dead letters if there is no response
+        let update = get_chat_messages(updates).last().map(|(update, _)| update);
+        let _typing = tg_bot.typing(chat_id);
+        if streaming {
+            stream_response(chat_id, update, tg_bot, gpt, fallback.as_deref(), pipeline).await;
+        } else {
+            send_response(chat_id, update, tg_bot, gpt, fallback.as_deref(), pipeline).await;
+        }
+    }
+
+    match gpt.summarize().await {
+        Ok(Some(summary)) => {
+            pipeline.run(Stage::Summary, None, || db.write_chat_value(chat_id, ChatKey::Summary, Some(summary.to_string()))).await;
+        }
+        Ok(None) => {}
+        Err(e) => error!("error summarizing history of chat {}: {:?}", chat_id, e),
+    }
+}
+
+/// Requests a completion for the history added to `gpt` and sends it to the chat.
+/// When the completion fails, `update` goes to dead letters and the `fallback` message is sent instead if there is one.
+async fn send_response<B: CompletionBackend>(chat_id: ChatId, update: Option<&Update>, tg_bot: &TgBot, gpt: &mut Gpt<B>, fallback: Option<&str>, pipeline: &Pipeline<'_>) {
+    let reply_to = gpt.reply_target();
+    let text = match pipeline.check(Stage::Completion, update, gpt.respond(None).await).await {
+        Some(text) => text,
+        None => match fallback {
+            Some(fallback) => fallback.to_string(),
+            None => return,
+        },
+    };
+
+    debug!("Response: {:?}", text);
+    pipeline.run(Stage::Send, update, || tg_bot.send_message(chat_id, &text, reply_to)).await;
+}
+
+/// Same as `send_response`, but the reply is shown while it is generated.
+async fn stream_response<B: CompletionBackend>(chat_id: ChatId, update: Option<&Update>, tg_bot: &TgBot, gpt: &mut Gpt<B>, fallback: Option<&str>, pipeline: &Pipeline<'_>) {
+    let reply_to = gpt.reply_target();
+    let (deltas, receiver) = mpsc::unbounded_channel();
+    let (response, placeholder) = tokio::join!(
+        gpt.respond(Some(deltas)),
+        tg_bot.stream_message(chat_id, reply_to, receiver),
+    );
+
+    match (response, placeholder) {
+        (Ok(text), Ok(placeholder)) => {
+            debug!("Streamed response: {:?}", text);
+            pipeline.run(Stage::Send, update, || tg_bot.finish_stream(&placeholder, &text)).await;
+        }
+        (Ok(text), Err(e)) => {
+            warn!("error streaming response to tg, sending it at once: {:?}", e);
+            pipeline.run(Stage::Send, update, || tg_bot.send_message(chat_id, &text, reply_to)).await;
+        }
+        (Err(e), placeholder) => {
+            pipeline.check(Stage::Completion, update, Err::<(), _>(e)).await;
+            match (placeholder, fallback) {
+                (Ok(placeholder), Some(fallback)) => {
+                    pipeline.run(Stage::Send, update, || tg_bot.finish_stream(&placeholder, fallback)).await;
+                }
+                (Ok(placeholder), None) => {
+                    pipeline.run(Stage::Send, update, || tg_bot.delete_message(&placeholder)).await;
+                }
+                (Err(_), Some(fallback)) => {
+                    pipeline.run(Stage::Send, update, || tg_bot.send_message(chat_id, fallback, reply_to)).await;
+                }
+                (Err(_), None) => {}
+            }
+        }
+    }
+}
+
+/// Executes the command if the sender is a bot admin, returns the reply to the command.
+async fn handle_command<B: CompletionBackend>(command: Command, message: &Message, db: &Db, gpt: &mut Gpt<B>) -> Result<String> {
+    let is_bot_admin = match message.from() {
+        Some(user) => db.is_bot_admin(user.id).await?,
+        None => false,
+    };
+
+    if !is_bot_admin {
+        info!("Command {:?} refused for non-admin user", command);
+        return Ok("Sorry, only bot admins can use this command".to_string());
+    }
+
+    info!("Executing command {:?}", command);
+    command.execute(db, message.chat.id, gpt).await
+}
+
+fn get_chat_messages<'a, 'u>(tg_updates: &'a [&'u Update]) -> impl Iterator<Item = (&'u Update, &'u Message)> + 'a {
     tg_updates.iter()
-        .filter(|u| u.chat_id() == Some(chat_id))
-        .filter_map(|u| match &u.kind {
-            UpdateKind::Message(m) => Some(m.into()),
+        .filter_map(|&u| match &u.kind {
+            UpdateKind::Message(m) => Some((u, m)),
             _ => None
         })
+}
+
+fn get_chat_updates(tg_updates: &[&Update], tg_bot: &TgBot) -> Vec<ChatMessage> {
+    get_chat_messages(tg_updates)
+        .map(|(_, m)| m)
+        .filter(|m| Command::from_message(m, tg_bot.username()).is_none())
+        .map(|m| ChatMessage::new(m, tg_bot.mentions_bot(m), tg_bot.replies_to_bot(m)))
         .collect::<Vec<_>>()
 }
 
-fn get_env(key: &str) -> Result<String, String> {
-    env::var(key).map_err(|_| format!("Couldn't read environment variable '{}'", key))
+fn get_env(key: &str) -> Result<String> {
+    env::var(key).map_err(|_| Error::Config(format!("Couldn't read environment variable '{}'", key)))
+}
+
+/// Writes the setting of the chat if one is given, otherwise the default for all chats.
+async fn write_setting(db: &Db, chat_id: Option<ChatId>, chat_key: ChatKey, conf_key: ConfKey, value: String) -> Result<()> {
+    match chat_id {
+        Some(chat_id) => db.write_chat_value(chat_id, chat_key, Some(value)).await,
+        None => Ok(db.write_conf_value(conf_key, Some(value)).await?),
+    }
+}
+
+fn invalid_env<E: Display>(key: &str) -> impl FnOnce(E) -> Error + '_ {
+    move |e| Error::Config(format!("Invalid value of environment variable '{}': {}", key, e))
 }
@@ -0,0 +1,200 @@
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::time::Duration;
+use log::{error, warn};
+use teloxide::types::Update;
+use thiserror::Error;
+use tokio::time::sleep;
+use crate::db::Db;
+use crate::error::Error as BotError;
+
+const STAGE_RETRIES: u32 = 3;
+const STAGE_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Steps of processing a batch of updates, each with its own failure policy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Stage {
+    SaveUpdate,
+    SaveUser,
+    Settings,
+    Command,
+    Completion,
+    Send,
+    Summary,
+    Inbox,
+    Claim,
+    Processed,
+    Prune,
+}
+
+/// What happens when a stage fails. The batch always goes on with the next stage.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    /// log the error
+    Skip,
+    /// run the stage again a few times, then log the error
+    Retry,
+    /// store the update with the error in the dead_letters table
+    DeadLetter,
+}
+
+impl Stage {
+    pub fn policy(self) -> Policy {
+        match self {
+            Stage::SaveUpdate | Stage::Send | Stage::Inbox | Stage::Claim | Stage::Processed => Policy::Retry,
+            Stage::SaveUser | Stage::Settings | Stage::Summary | Stage::Prune => Policy::Skip,
+            // the backend retries on its own, the message the bot didn't answer is kept
+            Stage::Command | Stage::Completion => Policy::DeadLetter,
+        }
+    }
+}
+
+impl Display for Stage {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Stage::SaveUpdate => write!(f, "saving update"),
+            Stage::SaveUser => write!(f, "saving user"),
+            Stage::Settings => write!(f, "reading settings"),
+            Stage::Command => write!(f, "executing command"),
+            Stage::Completion => write!(f, "requesting completion"),
+            Stage::Send => write!(f, "sending message"),
+            Stage::Summary => write!(f, "saving summary"),
+            Stage::Inbox => write!(f, "committing updates to the inbox"),
+            Stage::Claim => write!(f, "claiming updates"),
+            Stage::Processed => write!(f, "marking updates as processed"),
+            Stage::Prune => write!(f, "pruning the inbox"),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[error("{stage} failed{}: {source}", .update_id.map(|id| format!(" for update {}", id)).unwrap_or_default())]
+pub struct PipelineError {
+    pub stage: Stage,
+    pub update_id: Option<i32>,
+    pub source: BotError,
+}
+
+/// Applies the stage policies, so that a failing stage never stops processing.
+pub struct Pipeline<'a> {
+    db: &'a Db,
+    retry_delay: Duration,
+}
+
+impl<'a> Pipeline<'a> {
+    pub fn new(db: &'a Db) -> Self {
+        Self { db, retry_delay: STAGE_RETRY_DELAY }
+    }
+
+    /// Runs the stage, again if its policy is `Retry`, and applies the policy if it still fails.
+    /// Returns `None` if the stage failed.
+    pub async fn run<T, E, F, Fut>(&self, stage: Stage, update: Option<&Update>, mut attempt: F) -> Option<T>
+        where E: Into<BotError>,
+              F: FnMut() -> Fut,
+              Fut: Future<Output = Result<T, E>> {
+        let retries = if stage.policy() == Policy::Retry { STAGE_RETRIES } else { 0 };
+
+        let mut result = attempt().await;
+        for _ in 0..retries {
+            match &result {
+                Ok(_) => break,
+                Err(_) => warn!("{} failed, retrying in {:?}", stage, self.retry_delay),
+            }
+            sleep(self.retry_delay).await;
+            result = attempt().await;
+        }
+
+        self.check(stage, update, result).await
+    }
+
+    /// Applies the stage policy to the result of a stage which has already run, without retrying it.
+    pub async fn check<T, E: Into<BotError>>(&self, stage: Stage, update: Option<&Update>, result: Result<T, E>) -> Option<T> {
+        let source = match result {
+            Ok(value) => return Some(value),
+            Err(e) => e.into(),
+        };
+
+        let error = PipelineError { stage, update_id: update.map(|update| update.id), source };
+        match stage.policy() {
+            Policy::Skip | Policy::Retry => error!("{}, skipping", error),
+            Policy::DeadLetter => {
+                error!("{}, moving the update to dead letters", error);
+                if let Err(e) = self.db.save_dead_letter(update, &error.stage.to_string(), &error.source.to_string()).await {
+                    error!("error saving dead letter: {:?}", e);
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::error::Error as _;
+    use crate::fixtures::parse_update;
+    use super::*;
+
+    /// Fails `failures` times, then succeeds.
+    async fn flaky(attempts: &Cell<u32>, failures: u32) -> Result<u32, BotError> {
+        attempts.set(attempts.get() + 1);
+        match attempts.get() <= failures {
+            true => Err(BotError::Config(format!("failure {}", attempts.get()))),
+            false => Ok(attempts.get()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_retry_stage_is_repeated() {
+        let db = Db::in_memory().await;
+        let pipeline = Pipeline { db: &db, retry_delay: Duration::ZERO };
+        let attempts = Cell::new(0);
+
+        assert_eq!(pipeline.run(Stage::Inbox, None, || flaky(&attempts, 2)).await, Some(3));
+
+        attempts.set(0);
+        assert_eq!(pipeline.run(Stage::Inbox, None, || flaky(&attempts, 10)).await, None);
+        assert_eq!(attempts.get(), STAGE_RETRIES + 1);
+    }
+
+    #[tokio::test]
+    async fn test_skip_stage_runs_once() {
+        let db = Db::in_memory().await;
+        let pipeline = Pipeline { db: &db, retry_delay: Duration::ZERO };
+        let attempts = Cell::new(0);
+
+        assert_eq!(pipeline.run(Stage::SaveUser, None, || flaky(&attempts, 1)).await, None);
+        assert_eq!(attempts.get(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_dead_letter_stage_keeps_failed_update() {
+        let db = Db::in_memory().await;
+        let pipeline = Pipeline { db: &db, retry_delay: Duration::ZERO };
+        let update = parse_update(include_str!("../fixtures/updates/message.json"));
+        let attempts = Cell::new(0);
+
+        assert_eq!(pipeline.run(Stage::Command, Some(&update), || flaky(&attempts, 1)).await, None);
+        assert_eq!(attempts.get(), 1);
+        // the batch goes on with the next stage
+        assert_eq!(pipeline.run(Stage::Summary, Some(&update), || flaky(&attempts, 1)).await, Some(2));
+
+        let dead_letters = db.dead_letters().await.unwrap();
+        assert_eq!(dead_letters, vec![(Some(10001), "executing command".to_string(), "configuration error: failure 1".to_string())]);
+    }
+
+    #[test]
+    fn test_response_policies() {
+        assert_eq!(Stage::Completion.policy(), Policy::DeadLetter);
+        assert_eq!(Stage::Send.policy(), Policy::Retry);
+    }
+
+    #[test]
+    fn test_error_display() {
+        let error = PipelineError { stage: Stage::Command, update_id: Some(42), source: BotError::Config("no such chat".to_string()) };
+
+        assert_eq!(error.to_string(), "executing command failed for update 42: configuration error: no such chat");
+        assert_eq!(error.source().unwrap().to_string(), "configuration error: no such chat");
+    }
+}
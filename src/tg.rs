@@ -1,58 +1,265 @@
-use log::{debug, info};
-use std::error::Error;
+use log::{debug, info, warn};
+use std::io;
+use std::time::Duration;
 use teloxide::payloads::GetUpdates;
 use teloxide::prelude::*;
 use teloxide::requests::JsonRequest;
+use teloxide::types::{AllowedUpdate, ChatAction, MessageId, User};
 use teloxide::types::AllowedUpdate::*;
+use teloxide::{ApiError, RequestError};
+use tokio::sync::{mpsc, Mutex};
+use tokio::time::{sleep, timeout, Instant};
+use tokio_util::sync::{CancellationToken, DropGuard};
+use crate::chunker;
+use crate::db::Db;
+use crate::error::Result;
+use crate::webhook::{self, WebhookConfig};
+
+/// Shown until the first part of a streamed reply arrives.
+const STREAM_PLACEHOLDER: &str = "…";
+/// Telegram allows about 20 messages per minute in a group, edits included.
+const STREAM_EDIT_INTERVAL: Duration = Duration::from_secs(3);
+
+/// A typing status lasts 5 seconds or until the bot sends a message.
+const TYPING_INTERVAL: Duration = Duration::from_secs(4);
 
 pub struct TgBot {
     lp_timeout: u32,
     bot: Bot,
+    me: User,
+    source: UpdateSource,
+}
+
+enum UpdateSource {
+    LongPolling,
+    Webhook(Mutex<mpsc::Receiver<Update>>),
 }
 
 impl TgBot {
-    pub async fn new<'a>(token: String, lp_timeout: u32) -> Result<Self, Box<dyn Error>> {
+    pub async fn new(token: String, lp_timeout: u32) -> Result<Self> {
         let bot = Bot::new(token);
-        let me = bot.get_me().send().await?;
-        info!("I am: {:?}", me.user);
+        let me = get_me(&bot).await?;
+
+        // getUpdates is refused by Telegram while a webhook is set
+        bot.delete_webhook().send().await?;
 
         Ok(Self {
             bot,
             lp_timeout,
+            me,
+            source: UpdateSource::LongPolling,
         })
     }
 
-    pub async fn send_message(&self, chat_id: ChatId, message: &String) -> ResponseResult<teloxide::prelude::Message> {
-        self.bot.send_message(chat_id, message).send().await
+    /// Receives updates from Telegram via webhook, `lp_timeout` is how long `get_updates` waits for a batch.
+    /// The updates are already in the inbox of `db` when `get_updates` returns them.
+    pub async fn with_webhook(token: String, lp_timeout: u32, config: WebhookConfig, db: Db, cancellation_token: CancellationToken) -> Result<Self> {
+        let bot = Bot::new(token);
+        let me = get_me(&bot).await?;
+
+        let receiver = webhook::listen(&config, db, cancellation_token)?;
+        bot.set_webhook(config.url.clone())
+            .secret_token(config.secret_token)
+            .allowed_updates(allowed_updates())
+            .send()
+            .await?;
+        info!("Webhook has been set to {}", config.url);
+
+        Ok(Self {
+            bot,
+            lp_timeout,
+            me,
+            source: UpdateSource::Webhook(Mutex::new(receiver)),
+        })
+    }
+
+    /// Polled updates are committed to the inbox by the caller, together with the offset.
+    pub fn is_polling(&self) -> bool {
+        matches!(self.source, UpdateSource::LongPolling)
+    }
+
+    pub fn username(&self) -> &str {
+        self.me.username.as_deref().unwrap_or_default()
+    }
+
+    pub fn mentions_bot(&self, message: &teloxide::prelude::Message) -> bool {
+        let mention = format!("@{}", self.username()).to_lowercase();
+        message.text().map_or(false, |text| text.to_lowercase().contains(&mention))
+    }
+
+    pub fn replies_to_bot(&self, message: &teloxide::prelude::Message) -> bool {
+        message.reply_to_message()
+            .and_then(|reply| reply.from())
+            .map_or(false, |user| user.id == self.me.id)
+    }
+
+    /// Sends the text, split into several messages if it exceeds Telegram's message length limit.
+    /// Only the first part is sent as a reply.
+    pub async fn send_message(&self, chat_id: ChatId, message: &str, reply_to: Option<MessageId>) -> ResponseResult<Vec<teloxide::prelude::Message>> {
+        let parts = chunker::split_message(message, chunker::MESSAGE_LIMIT);
+        if parts.len() > 1 {
+            debug!("sending message in {} parts", parts.len());
+        }
+
+        let mut sent = Vec::with_capacity(parts.len());
+        for (i, part) in parts.into_iter().enumerate() {
+            let mut request = self.bot.send_message(chat_id, part);
+            if let (0, Some(reply_to)) = (i, reply_to) {
+                request = request.reply_to_message_id(reply_to).allow_sending_without_reply(true);
+            }
+            sent.push(request.send().await?);
+        }
+
+        Ok(sent)
+    }
+
+    /// Shows the bot as typing in the chat until the returned guard is dropped.
+    pub fn typing(&self, chat_id: ChatId) -> DropGuard {
+        let token = CancellationToken::new();
+        let cancelled = token.clone();
+        let bot = self.bot.clone();
+
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = cancelled.cancelled() => break,
+                    result = bot.send_chat_action(chat_id, ChatAction::Typing).send() => {
+                        if let Err(e) = result {
+                            debug!("error sending typing action: {:?}", e);
+                        }
+                    }
+                }
+                tokio::select! {
+                    _ = cancelled.cancelled() => break,
+                    _ = sleep(TYPING_INTERVAL) => {}
+                }
+            }
+        });
+
+        token.drop_guard()
+    }
+
+    /// Posts a placeholder and edits it with the text received from `deltas`, at most once per `STREAM_EDIT_INTERVAL`.
+    /// Returns the placeholder when `deltas` is closed, `finish_stream` then puts the final text in it.
+    pub async fn stream_message(&self, chat_id: ChatId, reply_to: Option<MessageId>, mut deltas: mpsc::UnboundedReceiver<String>) -> ResponseResult<teloxide::prelude::Message> {
+        let mut request = self.bot.send_message(chat_id, STREAM_PLACEHOLDER);
+        if let Some(reply_to) = reply_to {
+            request = request.reply_to_message_id(reply_to).allow_sending_without_reply(true);
+        }
+        let placeholder = request.send().await?;
+
+        let mut text = String::new();
+        let mut shown = String::new();
+        let mut last_edit = Instant::now();
+        while let Some(delta) = deltas.recv().await {
+            text.push_str(&delta);
+            if last_edit.elapsed() < STREAM_EDIT_INTERVAL {
+                continue;
+            }
+
+            // the rest of a long reply is sent as separate messages by finish_stream
+            let preview = chunker::split_message(&text, chunker::MESSAGE_LIMIT).into_iter().next().unwrap_or_default();
+            if !preview.is_empty() && preview != shown {
+                if let Err(e) = self.bot.edit_message_text(chat_id, placeholder.id, &preview).send().await {
+                    warn!("error editing streamed message: {:?}", e);
+                }
+                shown = preview;
+                last_edit = Instant::now();
+            }
+        }
+
+        Ok(placeholder)
+    }
+
+    /// Replaces the placeholder of a streamed reply with the final text, the parts over Telegram's
+    /// message length limit are sent as new messages. An empty reply removes the placeholder.
+    pub async fn finish_stream(&self, placeholder: &teloxide::prelude::Message, text: &str) -> ResponseResult<Vec<teloxide::prelude::Message>> {
+        let chat_id = placeholder.chat.id;
+        let mut parts = chunker::split_message(text, chunker::MESSAGE_LIMIT).into_iter();
+        let Some(first) = parts.next() else {
+            self.bot.delete_message(chat_id, placeholder.id).send().await?;
+            return Ok(Vec::new());
+        };
+
+        let mut sent = Vec::new();
+        match self.bot.edit_message_text(chat_id, placeholder.id, first).send().await {
+            Ok(message) => sent.push(message),
+            // the last streamed edit already shows the final text
+            Err(RequestError::Api(ApiError::MessageNotModified)) => sent.push(placeholder.clone()),
+            Err(e) => return Err(e),
+        }
+        for part in parts {
+            sent.push(self.bot.send_message(chat_id, part).send().await?);
+        }
+
+        Ok(sent)
+    }
+
+    pub async fn delete_message(&self, message: &teloxide::prelude::Message) -> ResponseResult<()> {
+        self.bot.delete_message(message.chat.id, message.id).send().await?;
+        Ok(())
     }
 
     pub async fn get_updates(&self, offset: Option<i32>) -> ResponseResult<Vec<Update>> {
-        let request = prepare_update_request(&self.bot, self.lp_timeout, offset);
-        debug!("requesting updates with offset: {:?}", request.offset);
+        match &self.source {
+            UpdateSource::LongPolling => {
+                let request = prepare_update_request(&self.bot, self.lp_timeout, offset);
+                debug!("requesting updates with offset: {:?}", request.offset);
+
+                request.send().await
+            }
+            UpdateSource::Webhook(receiver) => {
+                let mut receiver = receiver.lock().await;
+                receive_updates(&mut receiver, Duration::from_secs(self.lp_timeout as u64)).await
+            }
+        }
+    }
+}
+
+async fn get_me(bot: &Bot) -> ResponseResult<User> {
+    let me = bot.get_me().send().await?;
+    info!("I am: {:?}", me.user);
+
+    Ok(me.user)
+}
+
+/// Waits for the first update up to `wait`, then takes whatever else is already queued.
+async fn receive_updates(receiver: &mut mpsc::Receiver<Update>, wait: Duration) -> ResponseResult<Vec<Update>> {
+    let mut updates = Vec::new();
+    match timeout(wait, receiver.recv()).await {
+        Ok(Some(update)) => updates.push(update),
+        Ok(None) => return Err(RequestError::Io(io::Error::new(io::ErrorKind::BrokenPipe, "webhook server has stopped"))),
+        Err(_) => return Ok(updates),
+    }
 
-        request.send().await
+    while let Ok(update) = receiver.try_recv() {
+        updates.push(update);
     }
+
+    Ok(updates)
 }
 
 fn prepare_update_request(bot: &Bot, lp_timeout: u32, offset: Option<i32>) -> JsonRequest<GetUpdates> {
-    let mut request = bot.get_updates().timeout(lp_timeout).allowed_updates(vec![Message,
-                                                                                 EditedMessage,
-                                                                                 ChannelPost,
-                                                                                 EditedChannelPost,
-                                                                                 InlineQuery,
-                                                                                 ChosenInlineResult,
-                                                                                 CallbackQuery,
-                                                                                 ShippingQuery,
-                                                                                 PreCheckoutQuery,
-                                                                                 Poll,
-                                                                                 PollAnswer,
-                                                                                 MyChatMember,
-                                                                                 ChatMember,
-                                                                                 ChatJoinRequest,
-    ]);
+    let mut request = bot.get_updates().timeout(lp_timeout).allowed_updates(allowed_updates());
 
     request.offset = offset;
     request
 }
 
-
+fn allowed_updates() -> Vec<AllowedUpdate> {
+    vec![Message,
+         EditedMessage,
+         ChannelPost,
+         EditedChannelPost,
+         InlineQuery,
+         ChosenInlineResult,
+         CallbackQuery,
+         ShippingQuery,
+         PreCheckoutQuery,
+         Poll,
+         PollAnswer,
+         MyChatMember,
+         ChatMember,
+         ChatJoinRequest,
+    ]
+}
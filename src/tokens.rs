@@ -0,0 +1,112 @@
+use tiktoken_rs::CoreBPE;
+use tiktoken_rs::tokenizer::{get_tokenizer, Tokenizer};
+use crate::backend::CompletionMessage;
+
+/// Tokens added by the chat format around every message, see the OpenAI cookbook.
+const TOKENS_PER_MESSAGE: usize = 3;
+const TOKENS_PER_NAME: usize = 1;
+/// Every reply is primed with `<|start|>assistant<|message|>`.
+const REPLY_PRIMING: usize = 3;
+
+/// Room kept for the completion when `max_tokens` is not set.
+pub const DEFAULT_COMPLETION_RESERVE: usize = 1024;
+
+/// Context window of the model in tokens. Unknown models get 4096.
+pub fn context_size(model: &str) -> usize {
+    match model {
+        // the early snapshots had a smaller window than the current gpt-3.5-turbo
+        "gpt-3.5-turbo-0301" | "gpt-3.5-turbo-0613" => 4096,
+        _ => tiktoken_rs::model::get_context_size(model),
+    }
+}
+
+/// Drops the oldest messages until the system messages and the messages fit into `budget` tokens.
+/// The newest message is always kept, even if it alone exceeds the budget.
+pub fn trim_to_budget(model: &str, budget: usize, system: &[&str], messages: &mut Vec<CompletionMessage>) -> usize {
+    let (mut total, counts) = with_bpe(model, |bpe| {
+        let counts: Vec<usize> = messages.iter().map(|message| message_tokens(bpe, message)).collect();
+        let system: usize = system.iter().map(|text| TOKENS_PER_MESSAGE + count(bpe, text)).sum();
+        (REPLY_PRIMING + system + counts.iter().sum::<usize>(), counts)
+    });
+
+    let mut dropped = 0;
+    while total > budget && dropped + 1 < messages.len() {
+        total -= counts[dropped];
+        dropped += 1;
+    }
+    messages.drain(..dropped);
+
+    total
+}
+
+/// Runs `f` with the encoding of the model, cl100k for anything not known to use another one.
+fn with_bpe<T>(model: &str, f: impl FnOnce(&CoreBPE) -> T) -> T {
+    let bpe = match get_tokenizer(model) {
+        Some(Tokenizer::O200kBase) => tiktoken_rs::o200k_base_singleton(),
+        _ => tiktoken_rs::cl100k_base_singleton(),
+    };
+    let bpe = bpe.lock();
+    f(&bpe)
+}
+
+fn message_tokens(bpe: &CoreBPE, message: &CompletionMessage) -> usize {
+    let name = match &message.name {
+        Some(name) => TOKENS_PER_NAME + count(bpe, name),
+        None => 0,
+    };
+
+    TOKENS_PER_MESSAGE + count(bpe, &message.content) + name
+}
+
+fn count(bpe: &CoreBPE, text: &str) -> usize {
+    bpe.encode_with_special_tokens(text).len()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::Role;
+    use super::*;
+
+    fn message(content: &str) -> CompletionMessage {
+        CompletionMessage { role: Role::User, name: Some("1".to_string()), content: content.to_string() }
+    }
+
+    #[test]
+    fn test_context_size() {
+        assert_eq!(context_size("gpt-3.5-turbo-0301"), 4096);
+        assert_eq!(context_size("gpt-4"), 8192);
+        assert_eq!(context_size("my-local-model"), 4096);
+    }
+
+    #[test]
+    fn test_messages_within_budget_are_kept() {
+        let mut messages = vec![message("hello"), message("world")];
+
+        let total = trim_to_budget("gpt-4", 1000, &["prompt"], &mut messages);
+
+        assert_eq!(messages.len(), 2);
+        // "prompt", "hello", "world" and "1" are single tokens
+        assert_eq!(total, REPLY_PRIMING + TOKENS_PER_MESSAGE + 1 + 2 * (TOKENS_PER_MESSAGE + 1 + TOKENS_PER_NAME + 1));
+    }
+
+    #[test]
+    fn test_oldest_messages_are_dropped_first() {
+        let mut messages = vec![message(&"long ".repeat(100)), message("short"), message("last")];
+
+        let total = trim_to_budget("gpt-4", 50, &["prompt"], &mut messages);
+
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].content, "short");
+        assert!(total <= 50);
+    }
+
+    #[test]
+    fn test_newest_message_is_always_kept() {
+        let mut messages = vec![message("first"), message(&"long ".repeat(100))];
+
+        trim_to_budget("gpt-4", 50, &["prompt"], &mut messages);
+
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].content.starts_with("long"));
+    }
+}
@@ -0,0 +1,172 @@
+use rand::Rng;
+use regex::Regex;
+use teloxide::types::ChatId;
+use thiserror::Error;
+use crate::db::{ChatKey, Db};
+use crate::error::Result;
+use crate::gpt::ChatMessage;
+
+/// Decides whether the bot responds to a batch of new chat messages.
+/// Enabled conditions are combined, any of them is enough to respond.
+#[derive(Debug, Clone)]
+pub struct ResponseTrigger {
+    /// respond once `capacity` messages arrived since the last response
+    pub every_n: bool,
+    /// respond when the bot is mentioned by @username
+    pub mention: bool,
+    /// respond when a message replies to one of the bot's messages
+    pub reply: bool,
+    /// respond when a message matches the regex
+    pub keywords: Option<Regex>,
+    /// probability to respond to any message, between 0 and 1
+    pub chance: f64,
+}
+
+impl ResponseTrigger {
+    /// The behaviour before triggers were configurable: respond every `capacity` messages.
+    pub fn every_n() -> Self {
+        Self {
+            every_n: true,
+            mention: false,
+            reply: false,
+            keywords: None,
+            chance: 0.0,
+        }
+    }
+
+    /// Builds the trigger from a comma separated list of `every`, `mention` and `reply`,
+    /// plus an optional keyword regex and response chance.
+    pub fn parse(triggers: &str, keywords: Option<&str>, chance: Option<f64>) -> Result<Self, TriggerError> {
+        let mut trigger = Self {
+            every_n: false,
+            mention: false,
+            reply: false,
+            keywords: keywords.map(Regex::new).transpose().map_err(TriggerError::Keywords)?,
+            chance: validate_chance(chance.unwrap_or(0.0))?,
+        };
+
+        for name in triggers.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+            match name {
+                "every" => trigger.every_n = true,
+                "mention" => trigger.mention = true,
+                "reply" => trigger.reply = true,
+                _ => return Err(TriggerError::UnknownTrigger(name.to_string())),
+            }
+        }
+
+        Ok(trigger)
+    }
+
+    pub async fn read(db: &Db, chat_id: ChatId) -> Result<Self> {
+        let triggers = db.read_chat_value::<String>(chat_id, ChatKey::Triggers).await?.unwrap_or_default();
+        let keywords = db.read_chat_value::<String>(chat_id, ChatKey::TriggerKeywords).await?;
+        let chance = db.read_chat_value::<f64>(chat_id, ChatKey::TriggerChance).await?;
+
+        Ok(Self::parse(&triggers, keywords.as_deref(), chance)?)
+    }
+
+    /// `new_messages` are the messages just received, `pending` counts all messages since the last response.
+    pub fn should_respond(&self, new_messages: &[ChatMessage], pending: usize, capacity: usize) -> bool {
+        if self.every_n && pending >= capacity {
+            return true;
+        }
+
+        new_messages.iter().any(|message| {
+            (self.mention && message.mentions_bot)
+                || (self.reply && message.replies_to_bot)
+                || self.keywords.as_ref().map_or(false, |keywords| keywords.is_match(message.content()))
+                || (self.chance > 0.0 && rand::thread_rng().gen_bool(self.chance))
+        })
+    }
+}
+
+pub fn validate_chance(chance: f64) -> Result<f64, TriggerError> {
+    if !(0.0..=1.0).contains(&chance) {
+        return Err(TriggerError::Chance(chance));
+    }
+
+    Ok(chance)
+}
+
+#[derive(Debug, Error)]
+pub enum TriggerError {
+    #[error("unknown trigger '{0}', expected: every, mention, reply")]
+    UnknownTrigger(String),
+    #[error("invalid keywords regex: {0}")]
+    Keywords(#[source] regex::Error),
+    #[error("chance must be between 0 and 1, got {0}")]
+    Chance(f64),
+}
+
+#[cfg(test)]
+mod tests {
+    use teloxide::types::Message;
+    use super::*;
+
+    fn chat_message(text: &str, mentions_bot: bool, replies_to_bot: bool) -> ChatMessage {
+        let message: Message = serde_json::from_value(serde_json::json!({
+            "message_id": 1,
+            "from": { "id": 1001, "is_bot": false, "first_name": "Alice" },
+            "chat": { "id": -100200, "title": "Test group", "type": "supergroup" },
+            "date": 1684000000,
+            "text": text,
+        })).unwrap();
+
+        ChatMessage::new(&message, mentions_bot, replies_to_bot)
+    }
+
+    #[test]
+    fn test_parse() {
+        let trigger = ResponseTrigger::parse("every, mention", Some("(?i)pizza"), Some(0.5)).unwrap();
+
+        assert!(trigger.every_n);
+        assert!(trigger.mention);
+        assert!(!trigger.reply);
+        assert!(trigger.keywords.is_some());
+        assert_eq!(trigger.chance, 0.5);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(ResponseTrigger::parse("always", None, None), Err(TriggerError::UnknownTrigger(_))));
+        assert!(matches!(ResponseTrigger::parse("", Some("("), None), Err(TriggerError::Keywords(_))));
+        assert!(matches!(ResponseTrigger::parse("", None, Some(1.5)), Err(TriggerError::Chance(_))));
+    }
+
+    #[test]
+    fn test_every_n() {
+        let trigger = ResponseTrigger::every_n();
+        let messages = vec![chat_message("hi @bot", true, true)];
+
+        assert!(!trigger.should_respond(&messages, 2, 3));
+        assert!(trigger.should_respond(&messages, 3, 3));
+    }
+
+    #[test]
+    fn test_mention_and_reply() {
+        let trigger = ResponseTrigger::parse("mention,reply", None, None).unwrap();
+
+        assert!(trigger.should_respond(&[chat_message("hi", false, false), chat_message("hi @bot", true, false)], 2, 10));
+        assert!(trigger.should_respond(&[chat_message("yes", false, true)], 1, 10));
+        assert!(!trigger.should_respond(&[chat_message("hi", false, false)], 10, 10));
+    }
+
+    #[test]
+    fn test_keywords() {
+        let trigger = ResponseTrigger::parse("", Some(r"(?i)\bpizza\b"), None).unwrap();
+
+        assert!(trigger.should_respond(&[chat_message("Who wants Pizza?", false, false)], 1, 10));
+        assert!(!trigger.should_respond(&[chat_message("pizzeria", false, false)], 1, 10));
+    }
+
+    #[test]
+    fn test_chance() {
+        let always = ResponseTrigger::parse("", None, Some(1.0)).unwrap();
+        let never = ResponseTrigger::parse("", None, Some(0.0)).unwrap();
+        let messages = vec![chat_message("hi", false, false)];
+
+        assert!(always.should_respond(&messages, 1, 10));
+        assert!(!never.should_respond(&messages, 1, 10));
+        assert!(!always.should_respond(&[], 1, 10));
+    }
+}
@@ -0,0 +1,224 @@
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+use hyper::service::{make_service_fn, service_fn};
+use log::{debug, error, info};
+use reqwest::Url;
+use teloxide::types::Update;
+use tokio::sync::mpsc;
+use tokio_util::sync::CancellationToken;
+use crate::db::Db;
+
+const SECRET_TOKEN_HEADER: &str = "X-Telegram-Bot-Api-Secret-Token";
+const QUEUE_SIZE: usize = 256;
+
+pub struct WebhookConfig {
+    /// Public url registered with Telegram, usually served by a TLS terminating proxy
+    pub url: Url,
+    /// Local address the HTTP server binds to
+    pub address: SocketAddr,
+    pub secret_token: String,
+}
+
+struct Receiver {
+    path: String,
+    secret_token: String,
+    db: Db,
+    updates: mpsc::Sender<Update>,
+}
+
+/// Starts the HTTP server receiving updates from Telegram, runs until the token is cancelled.
+/// The updates are committed to the inbox of `db` before they are acknowledged.
+pub fn listen(config: &WebhookConfig, db: Db, cancellation_token: CancellationToken) -> Result<mpsc::Receiver<Update>, hyper::Error> {
+    let (tx, rx) = mpsc::channel(QUEUE_SIZE);
+    let receiver = Arc::new(Receiver {
+        path: config.url.path().to_string(),
+        secret_token: config.secret_token.clone(),
+        db,
+        updates: tx,
+    });
+
+    let make_service = make_service_fn(move |_| {
+        let receiver = receiver.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |request| {
+                let receiver = receiver.clone();
+                async move { Ok::<_, Infallible>(receiver.handle(request).await) }
+            }))
+        }
+    });
+
+    let server = Server::try_bind(&config.address)?
+        .serve(make_service)
+        .with_graceful_shutdown(async move { cancellation_token.cancelled().await });
+    info!("Webhook server is listening on {}", config.address);
+
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            error!("webhook server error: {:?}", e);
+        }
+        info!("webhook server stopped");
+    });
+
+    Ok(rx)
+}
+
+impl Receiver {
+    async fn handle(&self, request: Request<Body>) -> Response<Body> {
+        if request.method() != Method::POST || request.uri().path() != self.path {
+            return status(StatusCode::NOT_FOUND);
+        }
+
+        let secret_token = request.headers().get(SECRET_TOKEN_HEADER).map(|value| value.as_bytes());
+        if !secret_token.map_or(false, |token| constant_time_eq(token, self.secret_token.as_bytes())) {
+            debug!("webhook request with invalid secret token rejected");
+            return status(StatusCode::UNAUTHORIZED);
+        }
+
+        let body = match hyper::body::to_bytes(request.into_body()).await {
+            Ok(body) => body,
+            Err(e) => {
+                error!("error reading webhook request: {:?}", e);
+                return status(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        let update = match serde_json::from_slice::<Update>(&body) {
+            Ok(update) => update,
+            Err(e) => {
+                error!("error parsing webhook update: {:?}", e);
+                return status(StatusCode::BAD_REQUEST);
+            }
+        };
+
+        // Telegram redelivers the update until it is acknowledged, so it is acknowledged only once it is in the inbox
+        match self.db.receive_update(&update).await {
+            Ok(true) => {}
+            // redelivered, it has been passed on already
+            Ok(false) => {
+                debug!("update {} is in the inbox already", update.id);
+                return status(StatusCode::OK);
+            }
+            Err(e) => {
+                error!("error committing webhook update {} to the inbox: {:?}", update.id, e);
+                return status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+
+        // waiting here applies backpressure, an update which isn't passed on is resumed on the next start
+        if self.updates.send(update).await.is_err() {
+            debug!("updates are not processed anymore, the update is left in the inbox");
+        }
+        status(StatusCode::OK)
+    }
+}
+
+fn status(code: StatusCode) -> Response<Body> {
+    let mut response = Response::new(Body::empty());
+    *response.status_mut() = code;
+    response
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn receiver(db: Db) -> (Receiver, mpsc::Receiver<Update>) {
+        let (tx, rx) = mpsc::channel(1);
+        let receiver = Receiver {
+            path: "/tg/hook".to_string(),
+            secret_token: "s3cret".to_string(),
+            db,
+            updates: tx,
+        };
+
+        (receiver, rx)
+    }
+
+    fn request(path: &str, secret_token: Option<&str>, body: &str) -> Request<Body> {
+        let mut builder = Request::builder().method(Method::POST).uri(path);
+        if let Some(secret_token) = secret_token {
+            builder = builder.header(SECRET_TOKEN_HEADER, secret_token);
+        }
+
+        builder.body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_update_is_committed_and_forwarded() {
+        let db = Db::in_memory().await;
+        let (receiver, mut rx) = receiver(db.clone());
+        let body = include_str!("../fixtures/updates/message.json");
+
+        let response = receiver.handle(request("/tg/hook", Some("s3cret"), body)).await;
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(rx.try_recv().unwrap().id, 10001);
+        assert_eq!(db.received_updates().await.unwrap()[0].id, 10001);
+    }
+
+    #[tokio::test]
+    async fn test_redelivered_update_is_forwarded_once() {
+        let db = Db::in_memory().await;
+        let (receiver, mut rx) = receiver(db);
+        let body = include_str!("../fixtures/updates/message.json");
+
+        let first = receiver.handle(request("/tg/hook", Some("s3cret"), body)).await;
+        let second = receiver.handle(request("/tg/hook", Some("s3cret"), body)).await;
+
+        assert_eq!((first.status(), second.status()), (StatusCode::OK, StatusCode::OK));
+        assert_eq!(rx.try_recv().unwrap().id, 10001);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_update_is_not_acknowledged_if_not_committed() {
+        // no inbox table
+        let db = Db::new(":memory:".to_string()).await.unwrap();
+        let (receiver, mut rx) = receiver(db);
+        let body = include_str!("../fixtures/updates/message.json");
+
+        let response = receiver.handle(request("/tg/hook", Some("s3cret"), body)).await;
+
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_secret_token_is_rejected() {
+        let (receiver, mut rx) = receiver(Db::in_memory().await);
+        let body = include_str!("../fixtures/updates/message.json");
+
+        let response = receiver.handle(request("/tg/hook", Some("wrong"), body)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = receiver.handle(request("/tg/hook", None, body)).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_path_is_rejected() {
+        let (receiver, _rx) = receiver(Db::in_memory().await);
+        let body = include_str!("../fixtures/updates/message.json");
+
+        let response = receiver.handle(request("/other", Some("s3cret"), body)).await;
+
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_update_is_rejected() {
+        let (receiver, _rx) = receiver(Db::in_memory().await);
+
+        let response = receiver.handle(request("/tg/hook", Some("s3cret"), "{")).await;
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+}